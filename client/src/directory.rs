@@ -0,0 +1,64 @@
+use macaddr::MacAddr6;
+use shared::peer_list::{PeerInfo, PeerListAssembler};
+use std::{collections::HashMap, net::Ipv4Addr};
+
+/// Peers that came and went between two lists from the server
+#[derive(Default)]
+pub struct PeerChanges {
+    pub joined: Vec<PeerInfo>,
+    pub left: Vec<PeerInfo>,
+}
+
+/// Other clients on the network, from the peer list the server pushes
+#[derive(Default)]
+pub struct PeerDirectory {
+    assembler: PeerListAssembler,
+    peers: HashMap<MacAddr6, PeerInfo>,
+}
+
+impl PeerDirectory {
+    /// Add a page of the list, returns what changed once the whole list arrived
+    pub fn add(
+        &mut self,
+        generation: u64,
+        index: u8,
+        count: u8,
+        peers: Vec<PeerInfo>,
+    ) -> Option<PeerChanges> {
+        let peers = self.assembler.add(generation, index, count, peers)?;
+        let peers = peers
+            .into_iter()
+            .map(|peer| (peer.mac_address, peer))
+            .collect();
+        let old_peers = std::mem::replace(&mut self.peers, peers);
+        Some(PeerChanges {
+            joined: self
+                .peers
+                .values()
+                .filter(|peer| !old_peers.contains_key(&peer.mac_address))
+                .copied()
+                .collect(),
+            left: old_peers
+                .into_values()
+                .filter(|peer| !self.peers.contains_key(&peer.mac_address))
+                .collect(),
+        })
+    }
+
+    /// A new server session starts counting lists from the beginning again
+    pub fn reset_generation(&mut self) {
+        self.assembler = PeerListAssembler::default();
+    }
+
+    pub fn get(&self, mac_address: &MacAddr6) -> Option<&PeerInfo> {
+        self.peers.get(mac_address)
+    }
+
+    pub fn mac_addresses(&self) -> impl Iterator<Item = MacAddr6> + '_ {
+        self.peers.keys().copied()
+    }
+
+    pub fn ip(&self, mac_address: &MacAddr6) -> Option<Ipv4Addr> {
+        self.get(mac_address).map(|peer| peer.ip)
+    }
+}
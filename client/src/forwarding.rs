@@ -0,0 +1,135 @@
+use macaddr::MacAddr6;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// Entries not learned again in this long are forgotten, like the MAC table of a switch
+const AGING_TIME: Duration = Duration::from_secs(300);
+
+/// How frames to a MAC address get there
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Route {
+    Relay,
+    /// On the direct path to this peer, the address is the peer's own
+    /// or belongs to a host bridged behind it
+    Direct(MacAddr6),
+}
+
+/// Where to send broadcast and multicast frames
+#[derive(Clone, Copy)]
+pub enum BroadcastPolicy {
+    /// Through the server, which sends them to everyone
+    Relay,
+    /// On every direct path, through the server instead if some client has no direct path
+    /// so it doesn't miss them
+    Direct,
+    /// Through the server and on every direct path,
+    /// clients with a direct path get them twice but as soon as possible
+    Both,
+}
+
+impl FromStr for BroadcastPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "relay" => Ok(BroadcastPolicy::Relay),
+            "direct" => Ok(BroadcastPolicy::Direct),
+            "both" => Ok(BroadcastPolicy::Both),
+            _ => Err(format!(
+                "Unknown broadcast policy {policy}, expected relay, direct or both"
+            )),
+        }
+    }
+}
+
+struct Entry {
+    route: Route,
+    learned_at: Instant,
+}
+
+/// Routes learned from the source MAC address of frames written to TAP
+#[derive(Default)]
+pub struct ForwardingTable {
+    entries: HashMap<MacAddr6, Entry>,
+}
+
+impl ForwardingTable {
+    /// Frames through the relay don't replace a direct route, peers send broadcasts
+    /// through the server while their direct path is up
+    pub fn learn(&mut self, mac_address: MacAddr6, route: Route) {
+        let entry = self.entries.entry(mac_address).or_insert(Entry {
+            route,
+            learned_at: Instant::now(),
+        });
+        if matches!(route, Route::Direct(_))
+            || entry.route == Route::Relay
+            || entry.learned_at.elapsed() >= AGING_TIME
+        {
+            entry.route = route;
+            entry.learned_at = Instant::now();
+        }
+    }
+
+    pub fn lookup(&self, mac_address: &MacAddr6) -> Option<Route> {
+        self.entries
+            .get(mac_address)
+            .filter(|entry| entry.learned_at.elapsed() < AGING_TIME)
+            .map(|entry| entry.route)
+    }
+
+    /// The direct path to this peer is gone, with the routes to hosts behind it
+    pub fn forget(&mut self, peer_mac_address: &MacAddr6) {
+        self.entries.retain(|mac_address, entry| {
+            mac_address != peer_mac_address && entry.route != Route::Direct(*peer_mac_address)
+        });
+    }
+
+    pub fn purge_aged(&mut self) {
+        self.entries
+            .retain(|_, entry| entry.learned_at.elapsed() < AGING_TIME);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: MacAddr6 = MacAddr6::new(2, 0, 0, 0, 0, 1);
+    const BRIDGED: MacAddr6 = MacAddr6::new(2, 0, 0, 0, 0, 2);
+
+    #[test]
+    fn relay_does_not_replace_direct() {
+        let mut table = ForwardingTable::default();
+        table.learn(PEER, Route::Relay);
+        assert_eq!(table.lookup(&PEER), Some(Route::Relay));
+        table.learn(PEER, Route::Direct(PEER));
+        table.learn(PEER, Route::Relay);
+        assert_eq!(table.lookup(&PEER), Some(Route::Direct(PEER)));
+    }
+
+    #[test]
+    fn bridged_hosts_move_between_peers() {
+        let other_peer = MacAddr6::new(2, 0, 0, 0, 0, 3);
+        let mut table = ForwardingTable::default();
+        table.learn(BRIDGED, Route::Direct(PEER));
+        assert_eq!(table.lookup(&BRIDGED), Some(Route::Direct(PEER)));
+        table.learn(BRIDGED, Route::Direct(other_peer));
+        assert_eq!(table.lookup(&BRIDGED), Some(Route::Direct(other_peer)));
+    }
+
+    #[test]
+    fn forgetting_a_peer_forgets_hosts_behind_it() {
+        let mut table = ForwardingTable::default();
+        table.learn(PEER, Route::Direct(PEER));
+        table.learn(BRIDGED, Route::Direct(PEER));
+        let relayed = MacAddr6::new(2, 0, 0, 0, 0, 4);
+        table.learn(relayed, Route::Relay);
+        table.forget(&PEER);
+        assert_eq!(table.lookup(&PEER), None);
+        assert_eq!(table.lookup(&BRIDGED), None);
+        assert_eq!(table.lookup(&relayed), Some(Route::Relay));
+    }
+}
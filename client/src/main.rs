@@ -1,13 +1,41 @@
+mod directory;
+mod forwarding;
+mod nat;
+mod path_mtu;
+mod peers;
 mod tap_device;
 
 use argh::FromArgs;
+use directory::{PeerChanges, PeerDirectory};
+use forwarding::{BroadcastPolicy, ForwardingTable, Route};
+use macaddr::MacAddr6;
+use nat::NatType;
+use peers::{PeerPath, PeerPaths};
 use shared::{
-    get_formatted_time, get_mac_addresses, log, receive_until_success, send,
-    setup_panic_logging_hook, Message,
+    auth::{register_proof, IdentityKey, Proof},
+    batch::Batcher,
+    compression::{decompress, encode_compressed, CompressionStats},
+    crypto::{parse_public_key, Handshake},
+    fragment::{encode_frame_within, Reassembler, MAX_DATAGRAM_LENGTH},
+    get_formatted_time, get_mac_addresses,
+    key_ring::KeyRing,
+    log,
+    pmtu::{
+        max_frame_length, max_plaintext_length, max_probe_length, probe, probe_datagram_length,
+    },
+    receive_until_success, send_data_plaintext_to, send_packet_to, send_to,
+    sequence::SequenceTracker,
+    setup_panic_logging_hook, version_mismatch_reason, Datagram, DecodeErrors, Message, OpenError,
+    Opened, Packet, ReceivePacket, SessionId, CAPABILITIES, CAPABILITY_BATCHING,
+    CAPABILITY_COMPRESSION, CAPABILITY_DIRECT, MAX_FRAME_LENGTH, PROTOCOL_VERSION,
+    RECEIVE_BUFFER_SIZE,
 };
-use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
-use std::thread::sleep;
+use std::sync::{Mutex, RwLock};
+use std::thread::{sleep, Thread};
 use std::time::{Duration, Instant};
 use std::{net::UdpSocket, thread};
 use tap_device::{setup_tap, Device, TapDevice};
@@ -17,9 +45,8 @@ fn setup_socket(server: &SocketAddr) -> UdpSocket {
         SocketAddr::V4(_) => "0.0.0.0:0",
         SocketAddr::V6(_) => "[::]:0",
     };
-    let socket = UdpSocket::bind(bind_address).expect("Can't bind to address");
-    socket.connect(server).expect("Can't connect to address");
-    socket
+    // Not connected to the server, direct paths to other clients use the same socket
+    UdpSocket::bind(bind_address).expect("Can't bind to address")
 }
 
 fn resolve_host(hostname_port: &str) -> Result<SocketAddr, String> {
@@ -35,6 +62,93 @@ struct Cli {
     /// server ip adrress like localhost:8000
     #[argh(positional, from_str_fn(resolve_host))]
     server: SocketAddr,
+    /// secret key of the network, same as the server's
+    #[argh(option)]
+    network_key: Option<String>,
+    /// file with this client's private key for servers using an identities file,
+    /// generated if it doesn't exist
+    #[argh(option)]
+    identity_key_file: Option<String>,
+    /// public key the server logs on start up, refuse to connect if the server can't prove it owns it
+    #[argh(option, from_str_fn(parse_public_key))]
+    server_public_key: Option<[u8; 32]>,
+    /// seconds before doing a new handshake to replace the session keys
+    #[argh(option, default = "120")]
+    rekey_interval: u64,
+    /// bytes sent and received before doing a new handshake to replace the session keys
+    #[argh(option, default = "1 << 30")]
+    rekey_bytes: u64,
+    /// compress data frames if the server supports it, saves bandwidth on metered links
+    /// at the cost of some CPU
+    #[argh(switch)]
+    compression: bool,
+    /// microseconds small frames can wait to be sent together in one datagram if the server
+    /// supports it, 0 sends every frame right away
+    #[argh(option, default = "0")]
+    batch_delay: u64,
+    /// send everything through the server instead of trying direct paths to other clients
+    #[argh(switch)]
+    relay_only: bool,
+    /// where broadcast and multicast frames go: relay through the server, direct on every
+    /// direct path, falling back to the server if some client has none, or both
+    #[argh(option, default = "BroadcastPolicy::Relay")]
+    broadcast: BroadcastPolicy,
+    /// lower the TAP MTU to what fits in one datagram on the path to the server once it's probed,
+    /// so frames aren't sent in fragments
+    #[argh(switch)]
+    adjust_mtu: bool,
+}
+
+/// What we prove to the server that we are allowed to join with
+enum Credential {
+    NetworkKey(String),
+    Identity(IdentityKey),
+}
+
+struct State {
+    server: SocketAddr,
+    credential: Credential,
+    server_public_key: Option<[u8; 32]>,
+    rekey_interval: Duration,
+    rekey_bytes: u64,
+    /// Capabilities we tell the server in hello
+    capabilities: u32,
+    /// Both sides have [`CAPABILITY_COMPRESSION`], set on every hello
+    compression: AtomicBool,
+    compression_stats: Mutex<CompressionStats>,
+    /// Longest a small frame waits for others to be sent with
+    batch_delay: Duration,
+    /// Both sides have [`CAPABILITY_BATCHING`], set on every hello
+    batching: AtomicBool,
+    batcher: Mutex<Batcher>,
+    /// Both sides have [`CAPABILITY_DIRECT`], set on every hello
+    direct: AtomicBool,
+    /// Direct paths to other clients the server introduced us to
+    peers: Mutex<PeerPaths>,
+    /// Which MAC addresses we get frames from on direct paths
+    forwarding: Mutex<ForwardingTable>,
+    broadcast_policy: BroadcastPolicy,
+    /// Other clients on the network, if the server pushes them
+    directory: Mutex<PeerDirectory>,
+    /// Detected after every register, decides how often to try direct paths
+    nat_type: Mutex<NatType>,
+    /// Longest datagram the path to the server carries, 0 until probed after every register
+    server_path_mtu: AtomicUsize,
+    adjust_mtu: bool,
+    /// Sequence number of the next data message to the server
+    next_sequence: AtomicU64,
+    /// Data messages from the server, reset on every full handshake
+    received_sequences: Mutex<SequenceTracker>,
+    /// Keys from handshakes with the server
+    keys: RwLock<KeyRing>,
+    /// Static public key the server proved it owns in the first full handshake,
+    /// pinned like `server_public_key` for later handshakes and used by rekeys
+    server_static_public_key: RwLock<Option<[u8; 32]>>,
+    /// Frames the server sent in fragments
+    reassembler: Mutex<Reassembler>,
+    decode_errors: DecodeErrors,
+    decryption_failures: AtomicU64,
+    replayed_packets: AtomicU64,
 }
 
 fn main() {
@@ -42,6 +156,14 @@ fn main() {
 
     setup_panic_logging_hook();
 
+    let credential = match (config.network_key, config.identity_key_file) {
+        (_, Some(identity_key_file)) => {
+            Credential::Identity(load_or_generate_identity_key(&identity_key_file))
+        }
+        (Some(network_key), None) => Credential::NetworkKey(network_key),
+        (None, None) => panic!("Either --network-key or --identity-key-file is needed"),
+    };
+
     log!("Starting up TAP device");
     let tap_device = &setup_tap();
     log!("TAP device started");
@@ -49,102 +171,990 @@ fn main() {
     log!("Connecting to server {}", config.server);
     let socket = &setup_socket(&config.server);
 
+    let mut capabilities = CAPABILITIES;
+    if !config.compression {
+        capabilities &= !CAPABILITY_COMPRESSION;
+    }
+    if config.batch_delay == 0 {
+        capabilities &= !CAPABILITY_BATCHING;
+    }
+    if config.relay_only {
+        capabilities &= !CAPABILITY_DIRECT;
+    }
+
+    let state = &State {
+        server: config.server,
+        credential,
+        server_public_key: config.server_public_key,
+        rekey_interval: Duration::from_secs(config.rekey_interval),
+        rekey_bytes: config.rekey_bytes,
+        capabilities,
+        compression: AtomicBool::new(false),
+        compression_stats: Mutex::new(CompressionStats::default()),
+        batch_delay: Duration::from_micros(config.batch_delay),
+        batching: AtomicBool::new(false),
+        batcher: Mutex::new(Batcher::default()),
+        direct: AtomicBool::new(false),
+        peers: Mutex::new(PeerPaths::default()),
+        forwarding: Mutex::new(ForwardingTable::default()),
+        broadcast_policy: config.broadcast,
+        directory: Mutex::new(PeerDirectory::default()),
+        nat_type: Mutex::new(NatType::default()),
+        server_path_mtu: AtomicUsize::new(0),
+        adjust_mtu: config.adjust_mtu,
+        next_sequence: AtomicU64::new(0),
+        received_sequences: Mutex::new(SequenceTracker::default()),
+        keys: RwLock::new(KeyRing::default()),
+        server_static_public_key: RwLock::new(None),
+        reassembler: Mutex::new(Reassembler::default()),
+        decode_errors: DecodeErrors::default(),
+        decryption_failures: AtomicU64::new(0),
+        replayed_packets: AtomicU64::new(0),
+    };
+
+    let (handshake_sender, handshake_receiver) = mpsc::channel();
     let (register_sender, register_receiver) = mpsc::channel();
     let (pong_sender, pong_receiver) = mpsc::channel();
+    let (address_sender, address_receiver) = mpsc::channel();
+    let (probe_sender, probe_receiver) = mpsc::channel();
+    let replies = Replies {
+        handshake: handshake_sender,
+        register: register_sender,
+        pong: pong_sender,
+        address: address_sender,
+        probe: probe_sender,
+    };
+    let (shutdown_sender, shutdown_receiver) = mpsc::channel();
+
+    // Ctrl-C or SIGTERM
+    ctrlc::set_handler(move || {
+        let _ = shutdown_sender.send(());
+    })
+    .expect("Can't set shutdown handler");
 
     thread::scope(|scope| {
-        scope.spawn(move || loop {
-            handle_message(socket, tap_device, &register_sender, &pong_sender);
+        scope.spawn(move || {
+            shutdown_receiver.recv().unwrap();
+            disconnect(socket, state);
         });
 
-        if let Err(reason) = register(socket, tap_device, &register_receiver) {
+        scope.spawn(move || {
+            let mut buffer = vec![0; RECEIVE_BUFFER_SIZE];
+            loop {
+                handle_packet(socket, tap_device, state, &mut buffer, &replies);
+            }
+        });
+
+        if let Err(reason) = connect(
+            socket,
+            tap_device,
+            state,
+            &handshake_receiver,
+            &register_receiver,
+        ) {
             panic!("Register failed: {reason}");
         }
 
-        scope.spawn(|| read_and_send(tap_device, socket));
+        let batch_flusher = (config.batch_delay > 0).then(|| {
+            scope
+                .spawn(|| flush_batches(socket, state))
+                .thread()
+                .clone()
+        });
+        scope.spawn(move || read_and_send(tap_device, socket, state, batch_flusher.as_ref()));
+
+        scope.spawn(move || {
+            detect_nat_type(socket, state, &address_receiver);
+            loop {
+                sleep(Duration::from_secs(5));
+                ping(
+                    socket,
+                    tap_device,
+                    state,
+                    &handshake_receiver,
+                    &register_receiver,
+                    &pong_receiver,
+                    &address_receiver,
+                );
+            }
+        });
+
+        scope.spawn(|| loop {
+            sleep(Duration::from_secs(2));
+            maintain_peers(socket, state);
+        });
+
+        scope.spawn(move || match path_mtu::set_dont_fragment(socket) {
+            Ok(()) => {
+                let mut probed_at = Instant::now();
+                loop {
+                    let reprobe = probed_at.elapsed() >= REPROBE_INTERVAL;
+                    if reprobe {
+                        probed_at = Instant::now();
+                    }
+                    probe_paths(socket, tap_device, state, &probe_receiver, reprobe);
+                    sleep(Duration::from_secs(10));
+                }
+            }
+            Err(error) => {
+                log!("Can't stop our datagrams getting fragmented, not probing path MTUs: {error}");
+            }
+        });
 
-        scope.spawn(move || loop {
-            sleep(Duration::from_secs(5));
-            ping(socket, tap_device, &register_receiver, &pong_receiver);
+        scope.spawn(|| loop {
+            sleep(Duration::from_secs(100));
+            log_stats(state);
         });
     });
 }
 
+fn load_or_generate_identity_key(identity_key_file: &str) -> IdentityKey {
+    let identity_key = match fs::read_to_string(identity_key_file) {
+        Ok(hex_secret) => IdentityKey::from_hex(&hex_secret)
+            .unwrap_or_else(|| panic!("Invalid private key in {identity_key_file}")),
+        Err(_) => {
+            let identity_key = IdentityKey::generate();
+            fs::write(identity_key_file, identity_key.to_hex()).unwrap_or_else(|error| {
+                panic!("Can't write private key to {identity_key_file}: {error}")
+            });
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(identity_key_file, fs::Permissions::from_mode(0o600))
+                    .unwrap_or_else(|error| panic!("Can't protect {identity_key_file}: {error}"));
+            }
+            log!("Generated a new identity key to {identity_key_file}");
+            identity_key
+        }
+    };
+    log!(
+        "Identity public key: {}, add it to the server's identities file to join",
+        hex::encode(identity_key.public_key())
+    );
+    identity_key
+}
+
+/// Encrypt and send a message to the server, dropped if we haven't done a handshake yet
+fn send_message(socket: &UdpSocket, state: &State, message: &Message) {
+    if let Some(session) = state.keys.read().unwrap().current() {
+        send_to(socket, session, message, &state.server);
+    }
+}
+
+fn send_data(socket: &UdpSocket, state: &State, ethernet_frame: &[u8]) {
+    if let Some(session) = state.keys.read().unwrap().current() {
+        if state.compression.load(Ordering::Relaxed) {
+            let mut compression_stats = state.compression_stats.lock().unwrap();
+            if let Some(plaintext) = encode_compressed(ethernet_frame) {
+                send_data_plaintext_to(
+                    socket,
+                    session,
+                    &plaintext,
+                    next_sequence(state),
+                    &state.server,
+                );
+                compression_stats.add_compressed(ethernet_frame.len(), &plaintext);
+                return;
+            }
+            compression_stats.add_raw(ethernet_frame.len());
+        }
+        for plaintext in encode_frame_within(ethernet_frame, server_max_plaintext_length(state)) {
+            send_data_plaintext_to(
+                socket,
+                session,
+                &plaintext,
+                next_sequence(state),
+                &state.server,
+            );
+        }
+    }
+}
+
+/// What fits in one datagram to the server
+fn server_max_plaintext_length(state: &State) -> usize {
+    match state.server_path_mtu.load(Ordering::Relaxed) {
+        0 => max_plaintext_length(MAX_DATAGRAM_LENGTH),
+        path_mtu => max_plaintext_length(path_mtu),
+    }
+}
+
+/// Send an already encoded data message to the server
+fn send_plaintext_data(socket: &UdpSocket, state: &State, plaintext: &[u8]) {
+    if let Some(session) = state.keys.read().unwrap().current() {
+        send_data_plaintext_to(
+            socket,
+            session,
+            plaintext,
+            next_sequence(state),
+            &state.server,
+        );
+    }
+}
+
+fn next_sequence(state: &State) -> u64 {
+    state.next_sequence.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Send a frame on the direct path to the peer its destination was learned behind,
+/// through the server otherwise. Frames from hosts bridged to our TAP only go on direct paths
+/// since the server only takes frames from our own MAC address
+fn send_unicast(
+    socket: &UdpSocket,
+    state: &State,
+    destination_mac_address: MacAddr6,
+    ethernet_frame: &[u8],
+    bridged: bool,
+    batch_flusher: Option<&Thread>,
+) {
+    let route = state
+        .forwarding
+        .lock()
+        .unwrap()
+        .lookup(&destination_mac_address);
+    if let Some(Route::Direct(peer_mac_address)) = route {
+        if let Some(path) = state.peers.lock().unwrap().direct_mut(&peer_mac_address) {
+            send_on_path(socket, path, ethernet_frame);
+            return;
+        }
+    }
+    request_direct_path(socket, state, destination_mac_address);
+    if !bridged {
+        batch_or_send_data(socket, state, ethernet_frame, batch_flusher);
+    }
+}
+
+/// Send a broadcast or multicast frame the way the broadcast policy says,
+/// ones from bridged hosts only on direct paths like in [`send_unicast`]
+fn send_broadcast(
+    socket: &UdpSocket,
+    state: &State,
+    mac_address: MacAddr6,
+    ethernet_frame: &[u8],
+    bridged: bool,
+    batch_flusher: Option<&Thread>,
+) {
+    let direct = state.direct.load(Ordering::Relaxed);
+    let (relay, fan_out) = match state.broadcast_policy {
+        _ if bridged => (false, direct),
+        BroadcastPolicy::Relay => (true, false),
+        BroadcastPolicy::Direct if direct && every_peer_direct(state, mac_address) => (false, true),
+        BroadcastPolicy::Direct => (true, false),
+        BroadcastPolicy::Both => (true, direct),
+    };
+    if fan_out {
+        for path in state.peers.lock().unwrap().direct_values_mut() {
+            send_on_path(socket, path, ethernet_frame);
+        }
+    }
+    if relay {
+        batch_or_send_data(socket, state, ethernet_frame, batch_flusher);
+    }
+}
+
+/// Every other client in the peer list has a direct path up, none would miss a fan out
+fn every_peer_direct(state: &State, mac_address: MacAddr6) -> bool {
+    let mac_addresses: Vec<_> = state
+        .directory
+        .lock()
+        .unwrap()
+        .mac_addresses()
+        .filter(|peer_mac_address| *peer_mac_address != mac_address)
+        .collect();
+    let mut peers = state.peers.lock().unwrap();
+    !mac_addresses.is_empty()
+        && mac_addresses
+            .iter()
+            .all(|peer_mac_address| peers.direct_mut(peer_mac_address).is_some())
+}
+
+fn send_on_path(socket: &UdpSocket, path: &mut PeerPath, ethernet_frame: &[u8]) {
+    for plaintext in encode_frame_within(ethernet_frame, path.max_plaintext_length()) {
+        send_data_plaintext_to(
+            socket,
+            path.session(),
+            &plaintext,
+            path.next_sequence,
+            &path.endpoint,
+        );
+        path.next_sequence += 1;
+    }
+}
+
+/// Ask the server to introduce us to the destination, so the next frames can go directly
+fn request_direct_path(socket: &UdpSocket, state: &State, destination_mac_address: MacAddr6) {
+    if !state.direct.load(Ordering::Relaxed) {
+        return;
+    }
+    // The server can't introduce us to clients that aren't there or can't take direct paths
+    let reachable = state
+        .directory
+        .lock()
+        .unwrap()
+        .get(&destination_mac_address)
+        .is_some_and(|peer| peer.endpoint.is_some());
+    let nat_type = *state.nat_type.lock().unwrap();
+    if reachable
+        && state
+            .peers
+            .lock()
+            .unwrap()
+            .should_request(destination_mac_address, nat_type)
+    {
+        send_message(
+            socket,
+            state,
+            &Message::PeerRequest {
+                mac_address: destination_mac_address,
+            },
+        );
+    }
+}
+
+/// Wait for more small frames to send them together if batching, send right away otherwise
+fn batch_or_send_data(
+    socket: &UdpSocket,
+    state: &State,
+    ethernet_frame: &[u8],
+    batch_flusher: Option<&Thread>,
+) {
+    let Some(batch_flusher) = batch_flusher.filter(|_| state.batching.load(Ordering::Relaxed))
+    else {
+        send_data(socket, state, ethernet_frame);
+        return;
+    };
+    let mut batcher = state.batcher.lock().unwrap();
+    if !Batcher::is_batchable(ethernet_frame) {
+        // Frames waiting before it go first
+        if let Some(plaintext) = batcher.take() {
+            send_plaintext_data(socket, state, &plaintext);
+        }
+        send_data(socket, state, ethernet_frame);
+        return;
+    }
+    let was_empty = batcher.is_empty();
+    if let Some(plaintext) = batcher.add(ethernet_frame) {
+        send_plaintext_data(socket, state, &plaintext);
+    }
+    // The flusher sleeps until a batch is started
+    if was_empty {
+        batch_flusher.unpark();
+    }
+}
+
+/// Send the waiting frames once the oldest one waited the batch delay
+fn flush_batches(socket: &UdpSocket, state: &State) -> ! {
+    loop {
+        let deadline = state.batcher.lock().unwrap().deadline(state.batch_delay);
+        match deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())) {
+            None => thread::park(),
+            Some(remaining) if !remaining.is_zero() => thread::park_timeout(remaining),
+            Some(_) => {
+                let mut batcher = state.batcher.lock().unwrap();
+                if let Some(plaintext) = batcher.take() {
+                    send_plaintext_data(socket, state, &plaintext);
+                }
+            }
+        }
+    }
+}
+
 enum RegisterResult {
+    Hello { version: u16, capabilities: u32 },
     Success { ip: Ipv4Addr, subnet_mask: Ipv4Addr },
     Fail { reason: String },
 }
 
-fn handle_message(
+/// Where the thread receiving packets passes on answers other threads are waiting for
+struct Replies {
+    handshake: Sender<HandshakeResult>,
+    register: Sender<RegisterResult>,
+    pong: Sender<()>,
+    address: Sender<AddressResult>,
+    probe: Sender<ProbeAck>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ProbeTarget {
+    Server,
+    Peer(MacAddr6),
+}
+
+/// A [`Message::MtuProbe`] got through
+struct ProbeAck {
+    target: ProbeTarget,
+    datagram_length: usize,
+}
+
+/// Answer to a [`Packet::AddressRequest`]
+struct AddressResult {
+    /// The server or its probe port
+    from: SocketAddr,
+    mapped_address: SocketAddr,
+    probe_port: Option<u16>,
+}
+
+enum HandshakeResult {
+    Cookie {
+        cookie: [u8; 16],
+    },
+    Response {
+        public_key: [u8; 32],
+        static_public_key: [u8; 32],
+        confirmation: [u8; 16],
+        session_id: SessionId,
+    },
+    /// Answer to a rekey sent over the current session
+    Rekey {
+        public_key: [u8; 32],
+        confirmation: [u8; 16],
+    },
+    /// The server doesn't talk our protocol version
+    VersionMismatch {
+        version: u16,
+    },
+}
+
+fn handle_packet(
     socket: &UdpSocket,
     tap_device: &Device,
-    register_sender: &Sender<RegisterResult>,
-    pong_sender: &Sender<()>,
+    state: &State,
+    buffer: &mut [u8],
+    replies: &Replies,
 ) {
-    match receive_until_success(socket).message {
-        Message::Data { ethernet_frame } => {
-            // log!("received data packet");
-            // let time = Instant::now();
-            match tap_device.write_non_mut(&ethernet_frame) {
-                Ok(bytes_written) => {
-                    if bytes_written < ethernet_frame.len() {
-                        log!(
-                            "{bytes_written} bytes recieved but only {} bytes written to TAP",
-                            ethernet_frame.len()
-                        );
-                    }
+    let ReceivePacket {
+        datagram,
+        source_address,
+    } = receive_until_success(socket, buffer, &state.decode_errors);
+    let packet = match datagram {
+        Datagram::Transport {
+            session_id,
+            counter,
+            ciphertext,
+        } if source_address != state.server => {
+            handle_peer_transport(
+                socket,
+                tap_device,
+                state,
+                replies,
+                session_id,
+                counter,
+                ciphertext,
+                source_address,
+            );
+            return;
+        }
+        Datagram::Transport {
+            session_id,
+            counter,
+            ciphertext,
+        } => {
+            let mut keys = state.keys.write().unwrap();
+            let opened = keys.open(counter, ciphertext);
+            let current_session_id = keys.current().map(|session| session.id());
+            drop(keys);
+            if let Some(sequence) = opened.as_ref().ok().and_then(Opened::data_sequence) {
+                state.received_sequences.lock().unwrap().receive(sequence);
+            }
+            match opened {
+                Ok(Opened::Data { ethernet_frame, .. }) => {
+                    write_relayed_frame(tap_device, state, ethernet_frame);
+                }
+                // For a session we replaced with a new handshake, the server drops those itself
+                Ok(Opened::Message(Message::Kick { .. } | Message::ServerShutdown))
+                    if Some(session_id) != current_session_id => {}
+                Ok(Opened::Message(message)) => {
+                    handle_message(message, socket, tap_device, state, replies);
                 }
-                Err(error) => {
-                    log!("Can't write to TAP with error: {error}");
+                Err(OpenError::Decrypt(_) | OpenError::NoSession) => {
+                    state.decryption_failures.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(OpenError::Replay) => {
+                    state.replayed_packets.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(OpenError::Decode(error)) => {
+                    log!("Can't decode message from server, error: {error}");
                 }
             }
-            // log!(
-            //     "wrote {} bytes to TAP device in {:?}",
-            //     ethernet_frame.len(),
-            //     time.elapsed()
-            // );
+            return;
+        }
+        // The server's probe port only answers address requests
+        Datagram::Packet(Packet::AddressResponse {
+            mapped_address,
+            probe_port,
+        }) if source_address.ip() == state.server.ip() => {
+            replies
+                .address
+                .send(AddressResult {
+                    from: source_address,
+                    mapped_address,
+                    probe_port,
+                })
+                .unwrap();
+            return;
+        }
+        // Only the server does handshakes with us
+        Datagram::Packet(_) | Datagram::OtherVersionHandshake { .. }
+            if source_address != state.server =>
+        {
+            return
+        }
+        Datagram::Packet(packet) => packet,
+        Datagram::OtherVersionHandshake { .. } => return,
+    };
+    match packet {
+        Packet::Cookie { cookie } => {
+            replies
+                .handshake
+                .send(HandshakeResult::Cookie { cookie })
+                .unwrap();
+        }
+        Packet::HandshakeResponse {
+            public_key,
+            static_public_key,
+            confirmation,
+            session_id,
+        } => {
+            replies
+                .handshake
+                .send(HandshakeResult::Response {
+                    public_key,
+                    static_public_key,
+                    confirmation,
+                    session_id,
+                })
+                .unwrap();
+        }
+        Packet::VersionMismatch { version } => {
+            replies
+                .handshake
+                .send(HandshakeResult::VersionMismatch { version })
+                .unwrap();
+        }
+        // Ignore invalid packets
+        _ => {}
+    }
+}
+
+/// Packets from other clients on direct paths
+#[allow(clippy::too_many_arguments)]
+fn handle_peer_transport(
+    socket: &UdpSocket,
+    tap_device: &Device,
+    state: &State,
+    replies: &Replies,
+    session_id: SessionId,
+    counter: u64,
+    ciphertext: &mut [u8],
+    source_address: SocketAddr,
+) {
+    let mut peers = state.peers.lock().unwrap();
+    let Some(path) = peers.get_by_session_mut(session_id) else {
+        state.decryption_failures.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    let opened = match path.open(counter, ciphertext) {
+        Ok(opened) => opened,
+        Err(OpenError::Decrypt(_) | OpenError::NoSession) => {
+            state.decryption_failures.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        Err(OpenError::Replay) => {
+            state.replayed_packets.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        Err(OpenError::Decode(error)) => {
+            log!(
+                "Can't decode message from {}, error: {error}",
+                path.mac_address
+            );
+            return;
+        }
+    };
+    // Only the peer has the key, follow it if its NAT moves it to another port
+    path.endpoint = source_address;
+    if let Some(sequence) = opened.data_sequence() {
+        path.received_sequences.receive(sequence);
+    }
+    match opened {
+        Opened::Data { ethernet_frame, .. } => {
+            write_peer_frame(tap_device, state, path.mac_address, ethernet_frame);
+        }
+        Opened::Message(Message::Fragment {
+            frame_id,
+            index,
+            count,
+            payload,
+            ..
+        }) => {
+            if let Some(ethernet_frame) = path.reassembler.add(frame_id, index, count, payload) {
+                write_peer_frame(tap_device, state, path.mac_address, &ethernet_frame);
+            }
+        }
+        Opened::Message(Message::Ping) => {
+            send_to(socket, path.session(), &Message::Pong, &path.endpoint);
+        }
+        Opened::Message(Message::MtuProbe { padding }) => {
+            let datagram_length = probe_datagram_length(padding.len()) as u32;
+            send_to(
+                socket,
+                path.session(),
+                &Message::MtuProbeAck { datagram_length },
+                &path.endpoint,
+            );
+        }
+        Opened::Message(Message::MtuProbeAck { datagram_length }) => {
+            // Nobody is listening when we can't probe, like where the don't fragment bit can't be set
+            let _ = replies.probe.send(ProbeAck {
+                target: ProbeTarget::Peer(path.mac_address),
+                datagram_length: datagram_length as usize,
+            });
+        }
+        Opened::Message(Message::Pong) => {
+            path.pong_received();
+            if !path.confirmed {
+                path.confirmed = true;
+                log!(
+                    "Direct path to {} at {} is up",
+                    peer_name(state, path.mac_address),
+                    path.endpoint
+                );
+            }
+        }
+        // Everything else only comes from the server
+        Opened::Message(_) => {}
+    }
+    // Pings and pongs keep the route to the peer itself from aging while the path is up
+    if path.confirmed {
+        state
+            .forwarding
+            .lock()
+            .unwrap()
+            .learn(path.mac_address, Route::Direct(path.mac_address));
+    }
+}
+
+/// Frame a peer sent us directly, from itself or a host bridged behind it, the route to its source
+/// is learned as that peer's path. Like the server does for relayed frames, peers can't send
+/// frames from the MAC address of another client
+fn write_peer_frame(
+    tap_device: &Device,
+    state: &State,
+    peer_mac_address: MacAddr6,
+    ethernet_frame: &[u8],
+) {
+    let Ok((source_mac_address, _)) = get_mac_addresses(ethernet_frame) else {
+        return;
+    };
+    // The directory has our own MAC address too
+    if source_mac_address != peer_mac_address
+        && state
+            .directory
+            .lock()
+            .unwrap()
+            .get(&source_mac_address)
+            .is_some()
+    {
+        return;
+    }
+    state
+        .forwarding
+        .lock()
+        .unwrap()
+        .learn(source_mac_address, Route::Direct(peer_mac_address));
+    write_to_tap(tap_device, ethernet_frame);
+}
+
+fn handle_message(
+    message: Message,
+    socket: &UdpSocket,
+    tap_device: &Device,
+    state: &State,
+    replies: &Replies,
+) {
+    match message {
+        Message::Challenge { nonce } => {
+            let mac_address = tap_device.get_mac().unwrap();
+            let proof = match &state.credential {
+                Credential::NetworkKey(network_key) => {
+                    Proof::NetworkKey(register_proof(network_key, &nonce, mac_address))
+                }
+                Credential::Identity(identity_key) => {
+                    identity_key.register_proof(&nonce, mac_address)
+                }
+            };
+            send_message(socket, state, &Message::ChallengeResponse { proof });
+        }
+        Message::Hello {
+            version,
+            capabilities,
+        } => {
+            replies
+                .register
+                .send(RegisterResult::Hello {
+                    version,
+                    capabilities,
+                })
+                .unwrap();
         }
         Message::RegisterSuccess { ip, subnet_mask } => {
-            register_sender
+            replies
+                .register
                 .send(RegisterResult::Success { ip, subnet_mask })
                 .unwrap();
         }
         Message::RegisterFail { reason } => {
-            register_sender
+            replies
+                .register
                 .send(RegisterResult::Fail { reason })
                 .unwrap();
         }
         Message::Pong => {
-            pong_sender.send(()).unwrap();
+            replies.pong.send(()).unwrap();
+        }
+        Message::MtuProbeAck { datagram_length } => {
+            let _ = replies.probe.send(ProbeAck {
+                target: ProbeTarget::Server,
+                datagram_length: datagram_length as usize,
+            });
+        }
+        Message::Fragment {
+            frame_id,
+            index,
+            count,
+            payload,
+            ..
+        } => {
+            let ethernet_frame = state
+                .reassembler
+                .lock()
+                .unwrap()
+                .add(frame_id, index, count, payload);
+            if let Some(ethernet_frame) = ethernet_frame {
+                write_relayed_frame(tap_device, state, &ethernet_frame);
+            }
+        }
+        Message::CompressedData {
+            frame_length,
+            compressed_frame,
+            ..
+        } => match decompress(frame_length, &compressed_frame) {
+            Some(ethernet_frame) => {
+                state
+                    .compression_stats
+                    .lock()
+                    .unwrap()
+                    .add(ethernet_frame.len(), compressed_frame.len());
+                write_relayed_frame(tap_device, state, &ethernet_frame);
+            }
+            None => {
+                log!("Can't decompress frame from server");
+            }
+        },
+        Message::Batch {
+            ethernet_frames, ..
+        } => {
+            for ethernet_frame in &ethernet_frames {
+                write_relayed_frame(tap_device, state, ethernet_frame);
+            }
+        }
+        Message::PeerIntroduction {
+            mac_address,
+            endpoint,
+            session_id,
+            key,
+            initiator,
+        } => {
+            add_peer(
+                socket,
+                state,
+                mac_address,
+                endpoint,
+                &key,
+                initiator,
+                session_id,
+            );
+        }
+        Message::PeerList {
+            generation,
+            index,
+            count,
+            peers,
+        } => {
+            let changes = state
+                .directory
+                .lock()
+                .unwrap()
+                .add(generation, index, count, peers);
+            if let Some(changes) = changes {
+                update_peers(tap_device, state, changes);
+            }
         }
-        // Ignore invalid pakcets
+        Message::Kick { reason } => {
+            log!("Kicked by server: {reason}");
+            std::process::exit(1);
+        }
+        Message::ServerShutdown => {
+            log!("Server is shutting down, stopping");
+            std::process::exit(0);
+        }
+        Message::ConfigUpdate { ip, subnet_mask } => {
+            log!("Server changed our IP to {ip}, subnet mask to {subnet_mask}, setting it to TAP");
+            if let Err(error) = tap_device.set_ip(ip, subnet_mask) {
+                log!("Failed to set TAP IP: {error:?}");
+            }
+        }
+        Message::RekeyResponse {
+            public_key,
+            confirmation,
+        } => {
+            replies
+                .handshake
+                .send(HandshakeResult::Rekey {
+                    public_key,
+                    confirmation,
+                })
+                .unwrap();
+        }
+        // Ignore invalid packets
         _ => {}
     }
 }
 
-fn read_and_send(tap_device: &Device, socket: &UdpSocket) -> ! {
+/// Start punching a direct path to a client the server introduced us to,
+/// it does the same so both NATs see packets going out to the other side
+fn add_peer(
+    socket: &UdpSocket,
+    state: &State,
+    mac_address: MacAddr6,
+    endpoint: SocketAddr,
+    key: &[u8; 32],
+    initiator: bool,
+    session_id: SessionId,
+) {
+    // Our socket only has the address family of the server
+    let endpoint = match (state.server, endpoint) {
+        (SocketAddr::V6(_), SocketAddr::V4(endpoint)) => {
+            SocketAddr::new(IpAddr::V6(endpoint.ip().to_ipv6_mapped()), endpoint.port())
+        }
+        (SocketAddr::V4(_), SocketAddr::V6(_)) => {
+            log!("Can't reach {mac_address} at {endpoint} from IPv4, going through the server");
+            return;
+        }
+        _ => endpoint,
+    };
+    log!(
+        "Server introduced us to {} at {endpoint}, trying a direct path",
+        peer_name(state, mac_address)
+    );
+    let mut path = PeerPath::new(mac_address, endpoint, key, initiator, session_id);
+    path.ping_sent();
+    send_to(socket, path.session(), &Message::Ping, &endpoint);
+    state.peers.lock().unwrap().insert(path);
+}
+
+/// Log who joined and left, paths to the ones that left are dropped
+fn update_peers(tap_device: &Device, state: &State, changes: PeerChanges) {
+    let mac_address = tap_device.get_mac().unwrap();
+    for peer in changes.joined {
+        if peer.mac_address != mac_address {
+            log!("Peer {} ({}) joined", peer.mac_address, peer.ip);
+        }
+    }
+    let mut forwarding = state.forwarding.lock().unwrap();
+    for peer in &changes.left {
+        log!("Peer {} ({}) left", peer.mac_address, peer.ip);
+        forwarding.forget(&peer.mac_address);
+    }
+    drop(forwarding);
+    state.peers.lock().unwrap().retain(|path| {
+        changes
+            .left
+            .iter()
+            .all(|peer| peer.mac_address != path.mac_address)
+    });
+}
+
+/// MAC address with the IP from the directory if we know it
+fn peer_name(state: &State, mac_address: MacAddr6) -> String {
+    match state.directory.lock().unwrap().ip(&mac_address) {
+        Some(ip) => format!("{mac_address} ({ip})"),
+        None => mac_address.to_string(),
+    }
+}
+
+/// Frame the server sent us, the route to its sender is learned as the relay
+fn write_relayed_frame(tap_device: &Device, state: &State, ethernet_frame: &[u8]) {
+    if let Ok((source_mac_address, _)) = get_mac_addresses(ethernet_frame) {
+        state
+            .forwarding
+            .lock()
+            .unwrap()
+            .learn(source_mac_address, Route::Relay);
+    }
+    write_to_tap(tap_device, ethernet_frame);
+}
+
+fn write_to_tap(tap_device: &Device, ethernet_frame: &[u8]) {
+    // let time = Instant::now();
+    match tap_device.write_non_mut(ethernet_frame) {
+        Ok(bytes_written) => {
+            if bytes_written < ethernet_frame.len() {
+                log!(
+                    "{bytes_written} bytes recieved but only {} bytes written to TAP",
+                    ethernet_frame.len()
+                );
+            }
+        }
+        Err(error) => {
+            log!("Can't write to TAP with error: {error}");
+        }
+    }
+    // log!(
+    //     "wrote {} bytes to TAP device in {:?}",
+    //     ethernet_frame.len(),
+    //     time.elapsed()
+    // );
+}
+
+fn read_and_send(
+    tap_device: &Device,
+    socket: &UdpSocket,
+    state: &State,
+    batch_flusher: Option<&Thread>,
+) -> ! {
     let mac_address = tap_device.get_mac().expect("Can't get TAP MAC address");
-    let mtu = tap_device.get_mtu().unwrap_or(1500);
-    let mut buffer = vec![0; mtu as usize];
+    // The MTU can change while we're running
+    let mut buffer = vec![0; MAX_FRAME_LENGTH];
     loop {
         match tap_device.read_non_mut(&mut buffer) {
             Ok(bytes_read) => {
                 let ethernet_frame = &buffer[..bytes_read];
                 match get_mac_addresses(ethernet_frame) {
-                    Ok((source_mac_address, _)) => {
-                        if source_mac_address != mac_address {
-                            log!("Not device source mac? {source_mac_address}");
-                            continue;
-                        };
+                    Ok((source_mac_address, destination_mac_address)) => {
+                        // From a host bridged to the TAP device
+                        let bridged = source_mac_address != mac_address;
                         // log!(
                         //     "TAP packet ({bytes_read} bytes) received (source: {source_mac_address}, dest: {destination_mac_address})"
                         // );
-                        send(
-                            socket,
-                            &Message::Data {
-                                ethernet_frame: ethernet_frame.to_vec(),
-                            },
-                        );
+                        if destination_mac_address.is_multicast() {
+                            send_broadcast(
+                                socket,
+                                state,
+                                mac_address,
+                                ethernet_frame,
+                                bridged,
+                                batch_flusher,
+                            );
+                        } else {
+                            send_unicast(
+                                socket,
+                                state,
+                                destination_mac_address,
+                                ethernet_frame,
+                                bridged,
+                                batch_flusher,
+                            );
+                        }
                     }
                     Err(_) => {
                         // Invalid packet
@@ -161,16 +1171,198 @@ fn read_and_send(tap_device: &Device, socket: &UdpSocket) -> ! {
     }
 }
 
+fn connect(
+    socket: &UdpSocket,
+    tap_device: &Device,
+    state: &State,
+    handshake_receiver: &Receiver<HandshakeResult>,
+    register_receiver: &Receiver<RegisterResult>,
+) -> Result<(), String> {
+    handshake(socket, state, handshake_receiver)?;
+    hello(socket, state, register_receiver)?;
+    register(socket, tap_device, state, register_receiver)
+}
+
+fn handshake(
+    socket: &UdpSocket,
+    state: &State,
+    handshake_receiver: &Receiver<HandshakeResult>,
+) -> Result<(), String> {
+    // The server wants a cookie proving we own our address first
+    let mut cookie = None;
+    // Retry handshake for 15 seconds
+    let start_time = Instant::now();
+    while start_time.elapsed() < Duration::from_secs(15) {
+        let handshake = Handshake::new();
+        clear_receiver(handshake_receiver);
+        send_packet_to(
+            socket,
+            &Packet::HandshakeInit {
+                public_key: handshake.public_key(),
+                cookie,
+            },
+            &state.server,
+        );
+        match handshake_receiver.recv_timeout(Duration::from_secs(5)) {
+            Ok(HandshakeResult::Cookie { cookie: new_cookie }) => {
+                cookie = Some(new_cookie);
+            }
+            Ok(HandshakeResult::Response {
+                public_key,
+                static_public_key,
+                confirmation,
+                session_id,
+            }) => {
+                let pinned_public_key = state
+                    .server_public_key
+                    .or(*state.server_static_public_key.read().unwrap());
+                if pinned_public_key
+                    .is_some_and(|pinned_public_key| pinned_public_key != static_public_key)
+                {
+                    log!(
+                        "Server public key {} doesn't match the expected one, ignoring",
+                        hex::encode(static_public_key)
+                    );
+                    continue;
+                }
+                match handshake.finish(public_key, static_public_key, &confirmation, session_id) {
+                    Ok(session) => {
+                        if pinned_public_key.is_none() {
+                            log!(
+                                "Server public key is {}, pass it with --server-public-key to verify the server",
+                                hex::encode(static_public_key)
+                            );
+                        }
+                        state.keys.write().unwrap().rotate(session);
+                        // The server numbers data messages per session,
+                        // and peer lists from the start again if it restarted
+                        *state.received_sequences.lock().unwrap() = SequenceTracker::default();
+                        state.directory.lock().unwrap().reset_generation();
+                        *state.server_static_public_key.write().unwrap() = Some(static_public_key);
+                        return Ok(());
+                    }
+                    Err(_) => {
+                        log!("Server handshake response doesn't match our keys, retrying");
+                    }
+                }
+            }
+            Ok(HandshakeResult::VersionMismatch { version }) => {
+                return Err(version_mismatch_reason(version));
+            }
+            _ => {}
+        }
+    }
+    Err("Handshake timeout".to_owned())
+}
+
+/// New keys for the current session, the exchange is encrypted with the current keys
+/// so the server knows which session it is for
+fn rekey(
+    socket: &UdpSocket,
+    state: &State,
+    handshake_receiver: &Receiver<HandshakeResult>,
+) -> Result<(), String> {
+    let (Some(session_id), Some(static_public_key)) = (
+        state
+            .keys
+            .read()
+            .unwrap()
+            .current()
+            .map(|session| session.id()),
+        *state.server_static_public_key.read().unwrap(),
+    ) else {
+        return Err("No session to rekey".to_owned());
+    };
+    // Retry rekey for 15 seconds
+    let start_time = Instant::now();
+    while start_time.elapsed() < Duration::from_secs(15) {
+        let handshake = Handshake::new();
+        clear_receiver(handshake_receiver);
+        send_message(
+            socket,
+            state,
+            &Message::Rekey {
+                public_key: handshake.public_key(),
+            },
+        );
+        if let Ok(HandshakeResult::Rekey {
+            public_key,
+            confirmation,
+        }) = handshake_receiver.recv_timeout(Duration::from_secs(5))
+        {
+            match handshake.finish(public_key, static_public_key, &confirmation, session_id) {
+                Ok(session) => {
+                    state.keys.write().unwrap().rotate(session);
+                    return Ok(());
+                }
+                Err(_) => {
+                    log!("Server rekey response doesn't match our keys, retrying");
+                }
+            }
+        }
+    }
+    Err("Rekey timeout".to_owned())
+}
+
+/// Tell the server our protocol version and capabilities
+fn hello(
+    socket: &UdpSocket,
+    state: &State,
+    register_receiver: &Receiver<RegisterResult>,
+) -> Result<(), String> {
+    // Retry hello for 15 seconds
+    let start_time = Instant::now();
+    while start_time.elapsed() < Duration::from_secs(15) {
+        clear_receiver(register_receiver);
+        send_message(
+            socket,
+            state,
+            &Message::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: state.capabilities,
+            },
+        );
+        match register_receiver.recv_timeout(Duration::from_secs(5)) {
+            Ok(RegisterResult::Hello {
+                version,
+                capabilities,
+            }) => {
+                log!("Server uses protocol version {version}, capabilities: {capabilities:#x}");
+                let compression = capabilities & CAPABILITY_COMPRESSION != 0;
+                if state.capabilities & CAPABILITY_COMPRESSION != 0 && !compression {
+                    log!("Server doesn't support compression, sending data uncompressed");
+                }
+                state.compression.store(compression, Ordering::Relaxed);
+                let batching = capabilities & CAPABILITY_BATCHING != 0;
+                if state.capabilities & CAPABILITY_BATCHING != 0 && !batching {
+                    log!("Server doesn't support batching, sending every frame on its own");
+                }
+                state.batching.store(batching, Ordering::Relaxed);
+                let direct = capabilities & CAPABILITY_DIRECT != 0;
+                if state.capabilities & CAPABILITY_DIRECT != 0 && !direct {
+                    log!("Server doesn't support direct paths, sending everything through it");
+                }
+                state.direct.store(direct, Ordering::Relaxed);
+                return Ok(());
+            }
+            Ok(RegisterResult::Fail { reason }) => return Err(reason),
+            _ => {}
+        }
+    }
+    Err("Hello timeout".to_owned())
+}
+
 fn register(
     socket: &UdpSocket,
     tap_device: &Device,
+    state: &State,
     register_receiver: &Receiver<RegisterResult>,
 ) -> Result<(), String> {
     let mac_address = tap_device.get_mac().unwrap();
     // Retry register for 15 seconds
     let start_time = Instant::now();
     while start_time.elapsed() < Duration::from_secs(15) {
-        send(socket, &Message::Register { mac_address });
+        send_message(socket, state, &Message::Register { mac_address });
         clear_receiver(register_receiver);
         if let Ok(result) = register_receiver.recv_timeout(Duration::from_secs(5)) {
             match result {
@@ -185,6 +1377,8 @@ fn register(
                 RegisterResult::Fail { reason } => {
                     return Err(reason);
                 }
+                // Late answer to a retried hello
+                RegisterResult::Hello { .. } => {}
             }
         }
     }
@@ -194,13 +1388,26 @@ fn register(
 fn ping(
     socket: &UdpSocket,
     tap_device: &Device,
+    state: &State,
+    handshake_receiver: &Receiver<HandshakeResult>,
     register_receiver: &Receiver<RegisterResult>,
     pong_receiver: &Receiver<()>,
+    address_receiver: &Receiver<AddressResult>,
 ) {
+    state.decode_errors.log_and_reset();
+    let decryption_failures = state.decryption_failures.swap(0, Ordering::Relaxed);
+    if decryption_failures > 0 {
+        log!("Dropped {decryption_failures} packets that failed decryption");
+    }
+    let replayed_packets = state.replayed_packets.swap(0, Ordering::Relaxed);
+    if replayed_packets > 0 {
+        log!("Dropped {replayed_packets} replayed or too old packets");
+    }
+    rekey_if_needed(socket, state, handshake_receiver);
     // Retry ping for 15 seconds
     let start_time = Instant::now();
     while start_time.elapsed() < Duration::from_secs(15) {
-        send(socket, &Message::Ping);
+        send_message(socket, state, &Message::Ping);
         clear_receiver(pong_receiver);
         if pong_receiver.recv_timeout(Duration::from_secs(5)).is_ok() {
             // Pong received
@@ -211,11 +1418,281 @@ fn ping(
     // If didn't get a pong then we probably lost connection to server
     // try re-register
     log!("Lost connection to server, trying to re-register");
-    if let Err(reason) = register(socket, tap_device, register_receiver) {
+    if let Err(reason) = connect(
+        socket,
+        tap_device,
+        state,
+        handshake_receiver,
+        register_receiver,
+    ) {
         // log!("Re-register failed: {reason}");
         panic!("Re-register failed: {reason}");
     }
     log!("Re-register success");
+    // We might be behind another NAT now, on another path to the server
+    state.server_path_mtu.store(0, Ordering::Relaxed);
+    detect_nat_type(socket, state, address_receiver);
+}
+
+/// Ask the server and its probe port which address they see us at,
+/// a NAT giving us a different one for each of them is symmetric
+fn detect_nat_type(socket: &UdpSocket, state: &State, address_receiver: &Receiver<AddressResult>) {
+    let Some(response) = request_address(socket, &state.server, address_receiver) else {
+        log!("Server didn't tell us our address, NAT type unknown");
+        return;
+    };
+    let Some(probe_port) = response.probe_port else {
+        log!(
+            "Server sees us at {}, it has no probe port to tell our NAT type",
+            response.mapped_address
+        );
+        return;
+    };
+    let probe_address = SocketAddr::new(state.server.ip(), probe_port);
+    let Some(probe_response) = request_address(socket, &probe_address, address_receiver) else {
+        log!("Server probe port {probe_port} didn't answer, NAT type unknown");
+        return;
+    };
+    let nat_type = NatType::classify(response.mapped_address, probe_response.mapped_address);
+    log!(
+        "NAT type: {nat_type}, server sees us at {} and its probe port at {}",
+        response.mapped_address,
+        probe_response.mapped_address
+    );
+    *state.nat_type.lock().unwrap() = nat_type;
+}
+
+fn request_address(
+    socket: &UdpSocket,
+    to_address: &SocketAddr,
+    address_receiver: &Receiver<AddressResult>,
+) -> Option<AddressResult> {
+    // Retry address request for 6 seconds
+    let start_time = Instant::now();
+    while start_time.elapsed() < Duration::from_secs(6) {
+        clear_receiver(address_receiver);
+        send_packet_to(
+            socket,
+            &Packet::AddressRequest { padding: [0; 32] },
+            to_address,
+        );
+        if let Ok(result) = address_receiver.recv_timeout(Duration::from_secs(2)) {
+            if result.from == *to_address {
+                return Some(result);
+            }
+        }
+    }
+    None
+}
+
+fn rekey_if_needed(
+    socket: &UdpSocket,
+    state: &State,
+    handshake_receiver: &Receiver<HandshakeResult>,
+) {
+    let needs_rekey = state
+        .keys
+        .read()
+        .unwrap()
+        .current()
+        .is_some_and(|session| session.needs_rekey(state.rekey_interval, state.rekey_bytes));
+    if needs_rekey {
+        // The old keys keep working while the handshake is going on
+        // and for a while after it in case some packets are still on the way
+        if let Err(reason) = rekey(socket, state, handshake_receiver) {
+            log!("Rekey failed: {reason}");
+        }
+    }
+}
+
+/// Ping direct paths to keep their NAT mappings open and move them on to new keys when it's time,
+/// the ones that stopped answering are dropped and traffic goes through the server
+/// until it introduces us again
+fn maintain_peers(socket: &UdpSocket, state: &State) {
+    let mut peers = state.peers.lock().unwrap();
+    let mut forwarding = state.forwarding.lock().unwrap();
+    forwarding.purge_aged();
+    peers.retain(|path| {
+        let keep = path.is_alive();
+        if !keep {
+            forwarding.forget(&path.mac_address);
+            if path.confirmed {
+                log!(
+                    "Direct path to {} is down, going through the server",
+                    peer_name(state, path.mac_address)
+                );
+            } else {
+                log!(
+                    "Can't reach {} directly, going through the server",
+                    peer_name(state, path.mac_address)
+                );
+            }
+        }
+        keep
+    });
+    drop(forwarding);
+    for path in peers.values_mut() {
+        path.rekey_if_needed(state.rekey_interval, state.rekey_bytes);
+        path.ping_sent();
+        send_to(socket, path.session(), &Message::Ping, &path.endpoint);
+    }
+}
+
+/// Paths can get shorter, and with the don't fragment bit set on all our datagrams
+/// the ones that no longer fit are lost until the path is probed again
+const REPROBE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Probe the paths to the server and peers that haven't been yet, or all of them if `reprobe`
+fn probe_paths(
+    socket: &UdpSocket,
+    tap_device: &Device,
+    state: &State,
+    probe_receiver: &Receiver<ProbeAck>,
+    reprobe: bool,
+) {
+    if reprobe || state.server_path_mtu.load(Ordering::Relaxed) == 0 {
+        let path_mtu = probe_path(socket, state, ProbeTarget::Server, probe_receiver);
+        set_server_path_mtu(tap_device, state, path_mtu);
+    }
+    let to_probe: Vec<_> = state
+        .peers
+        .lock()
+        .unwrap()
+        .direct_values_mut()
+        .filter(|path| reprobe || path.mtu.is_none())
+        .map(|path| path.mac_address)
+        .collect();
+    for mac_address in to_probe {
+        let path_mtu = probe_path(
+            socket,
+            state,
+            ProbeTarget::Peer(mac_address),
+            probe_receiver,
+        );
+        if let Some(path) = state.peers.lock().unwrap().direct_mut(&mac_address) {
+            if path.mtu == Some(path_mtu.unwrap_or(MAX_DATAGRAM_LENGTH)) {
+                continue;
+            }
+            match path_mtu {
+                Some(path_mtu) => {
+                    log!(
+                        "Path MTU to {mac_address}: {path_mtu} bytes, frames up to {} bytes go in one datagram",
+                        max_frame_length(path_mtu)
+                    );
+                }
+                None => {
+                    log!("{MAX_DATAGRAM_LENGTH} bytes datagrams don't get through to {mac_address}, big frames will be lost");
+                }
+            }
+            path.mtu = Some(path_mtu.unwrap_or(MAX_DATAGRAM_LENGTH));
+        }
+    }
+}
+
+/// Longest datagram that gets through to `target` with a binary search,
+/// `None` if not even [`MAX_DATAGRAM_LENGTH`] ones do
+fn probe_path(
+    socket: &UdpSocket,
+    state: &State,
+    target: ProbeTarget,
+    probe_receiver: &Receiver<ProbeAck>,
+) -> Option<usize> {
+    let gets_through = |datagram_length| {
+        // Retry probe 3 times, so a lost probe isn't taken as too long
+        for _ in 0..3 {
+            clear_receiver(probe_receiver);
+            let message = probe(datagram_length);
+            match target {
+                ProbeTarget::Server => send_message(socket, state, &message),
+                ProbeTarget::Peer(mac_address) => {
+                    if let Some(path) = state.peers.lock().unwrap().direct_mut(&mac_address) {
+                        send_to(socket, path.session(), &message, &path.endpoint);
+                    }
+                }
+            }
+            if let Ok(ack) = probe_receiver.recv_timeout(Duration::from_secs(1)) {
+                if ack.target == target && ack.datagram_length == datagram_length {
+                    return true;
+                }
+            }
+        }
+        false
+    };
+    if !gets_through(MAX_DATAGRAM_LENGTH) {
+        return None;
+    }
+    let mut shortest_lost = max_probe_length(state.server.is_ipv6()) + 1;
+    let mut longest_through = MAX_DATAGRAM_LENGTH;
+    while shortest_lost - longest_through > 1 {
+        let datagram_length = (longest_through + shortest_lost) / 2;
+        if gets_through(datagram_length) {
+            longest_through = datagram_length;
+        } else {
+            shortest_lost = datagram_length;
+        }
+    }
+    Some(longest_through)
+}
+
+/// Send data frames up to what fits in one datagram on the path unfragmented,
+/// lower the TAP MTU to it or warn that bigger frames go in fragments, if it changed
+fn set_server_path_mtu(tap_device: &Device, state: &State, path_mtu: Option<usize>) {
+    let stored = path_mtu.unwrap_or(MAX_DATAGRAM_LENGTH);
+    if state.server_path_mtu.swap(stored, Ordering::Relaxed) == stored {
+        return;
+    }
+    let Some(path_mtu) = path_mtu else {
+        log!("{MAX_DATAGRAM_LENGTH} bytes datagrams don't get through to the server, big frames will be lost");
+        return;
+    };
+    // Without the ethernet header
+    let mtu = (max_frame_length(path_mtu) - 14) as u32;
+    log!("Path MTU to server: {path_mtu} bytes, frames up to MTU {mtu} go in one datagram");
+    let Ok(tap_mtu) = tap_device.get_mtu() else {
+        return;
+    };
+    if tap_mtu <= mtu {
+        return;
+    }
+    if !state.adjust_mtu {
+        log!("TAP MTU {tap_mtu} is bigger, those frames are sent in fragments, run with --adjust-mtu to lower it");
+        return;
+    }
+    match tap_device.set_mtu(mtu) {
+        Ok(()) => {
+            log!("Lowered TAP MTU from {tap_mtu} to {mtu}");
+        }
+        Err(error) => {
+            log!("Can't set TAP MTU to {mtu}: {error}");
+        }
+    }
+}
+
+/// Tell the server we're leaving so it frees our IP right away, then exit
+fn disconnect(socket: &UdpSocket, state: &State) -> ! {
+    log!("Disconnecting from server");
+    send_message(socket, state, &Message::Disconnect);
+    log_stats(state);
+    std::process::exit(0);
+}
+
+/// Data lost on the way from the server and direct paths since the last time
+/// and how much compression saved
+fn log_stats(state: &State) {
+    let sequence_stats = state.received_sequences.lock().unwrap().take_stats();
+    if sequence_stats.received > 0 {
+        log!("Data from server {sequence_stats}");
+    }
+    for path in state.peers.lock().unwrap().values_mut() {
+        let sequence_stats = path.received_sequences.take_stats();
+        if sequence_stats.received > 0 {
+            log!("Data from {} directly {sequence_stats}", path.mac_address);
+        }
+    }
+    let compression_stats = state.compression_stats.lock().unwrap();
+    if state.compression.load(Ordering::Relaxed) && compression_stats.original_bytes > 0 {
+        log!("Compression {compression_stats}");
+    }
 }
 
 fn clear_receiver<T>(receiver: &Receiver<T>) {
@@ -0,0 +1,37 @@
+use std::{fmt, net::SocketAddr};
+
+/// How our NAT maps the socket to public addresses, found by asking two ports of the server
+/// which address they see us at
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum NatType {
+    /// Not detected yet, or the server has no probe port
+    #[default]
+    Unknown,
+    /// The same public address for every destination, so peers can reach us
+    /// at the address the server sees
+    Cone,
+    /// A new public address for every destination, direct paths only work
+    /// if the peer's NAT lets our packets in from wherever they come
+    Symmetric,
+}
+
+impl NatType {
+    /// From the addresses the server's main port and probe port see us at
+    pub fn classify(mapped_address: SocketAddr, probe_mapped_address: SocketAddr) -> Self {
+        if mapped_address == probe_mapped_address {
+            NatType::Cone
+        } else {
+            NatType::Symmetric
+        }
+    }
+}
+
+impl fmt::Display for NatType {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(match self {
+            NatType::Unknown => "unknown",
+            NatType::Cone => "cone",
+            NatType::Symmetric => "symmetric",
+        })
+    }
+}
@@ -0,0 +1,45 @@
+use std::{io, net::UdpSocket};
+
+/// Have the kernel set the don't fragment bit on the socket's datagrams and send them
+/// even if they're longer than the MTU it knows for the route, so probes that are too long
+/// get lost on the way instead of fragmented. It stays set for all of them, not just probes
+#[cfg(target_os = "linux")]
+pub fn set_dont_fragment(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let (level, name, value) = if socket.local_addr()?.is_ipv6() {
+        (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_PROBE,
+        )
+    } else {
+        (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_PROBE,
+        )
+    };
+    // SAFETY: the socket is open and `value` outlives the call
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_dont_fragment(_socket: &UdpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "path MTU probing is only supported on Linux",
+    ))
+}
@@ -0,0 +1,280 @@
+use crate::nat::NatType;
+use macaddr::MacAddr6;
+use shared::{
+    crypto::{next_peer_key, peer_session, Session},
+    fragment::{Reassembler, MAX_DATAGRAM_LENGTH},
+    key_ring::KeyRing,
+    pmtu::max_plaintext_length,
+    sequence::SequenceTracker,
+    OpenError, Opened, SessionId,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Paths that didn't get a pong in this long after the introduction are given up on
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Direct paths that didn't answer this many pings in a row are dropped
+const MAX_MISSED_PONGS: u32 = 2;
+/// Data goes through the relay while a pong is this late, so little is lost on a path that died
+/// before it's dropped
+const PONG_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long to wait before asking the server for a path to the same peer again
+const REQUEST_INTERVAL: Duration = Duration::from_secs(30);
+/// Punching out of a symmetric NAT only works with some peers, don't keep trying as often
+const SYMMETRIC_REQUEST_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Direct path to another client, set up by the server introducing us to each other
+pub struct PeerPath {
+    pub mac_address: MacAddr6,
+    /// Where the peer's packets come from, it moves with the peer like sessions on the server
+    pub endpoint: SocketAddr,
+    session_id: SessionId,
+    initiator: bool,
+    keys: KeyRing,
+    /// Of the session after the current one, see [`next_peer_key`]
+    next_key: [u8; 32],
+    /// The peer answered a ping, so packets get through both ways and data can use this path
+    pub confirmed: bool,
+    pub introduced_at: Instant,
+    /// Our last ping hasn't been answered yet
+    awaiting_pong: bool,
+    ping_sent_at: Instant,
+    /// Pings in a row that weren't answered before the next one
+    missed_pongs: u32,
+    /// Frames the peer sent in fragments
+    pub reassembler: Reassembler,
+    /// Sequence number of the next data message to the peer
+    pub next_sequence: u64,
+    /// Data messages from the peer
+    pub received_sequences: SequenceTracker,
+    /// Longest datagram the path carries, once it's probed
+    pub mtu: Option<usize>,
+}
+
+impl PeerPath {
+    /// `key`, `initiator` and `session_id` are from the server's introduction
+    pub fn new(
+        mac_address: MacAddr6,
+        endpoint: SocketAddr,
+        key: &[u8; 32],
+        initiator: bool,
+        session_id: SessionId,
+    ) -> Self {
+        let next_key = next_peer_key(key);
+        let mut keys = KeyRing::default();
+        keys.rotate(peer_session(key, initiator, session_id));
+        keys.set_next(peer_session(&next_key, initiator, session_id));
+        Self {
+            mac_address,
+            endpoint,
+            session_id,
+            initiator,
+            keys,
+            next_key,
+            confirmed: false,
+            introduced_at: Instant::now(),
+            awaiting_pong: false,
+            ping_sent_at: Instant::now(),
+            missed_pongs: 0,
+            reassembler: Reassembler::default(),
+            next_sequence: 0,
+            received_sequences: SequenceTracker::default(),
+            mtu: None,
+        }
+    }
+
+    /// What fits in one datagram to the peer
+    pub fn max_plaintext_length(&self) -> usize {
+        max_plaintext_length(self.mtu.unwrap_or(MAX_DATAGRAM_LENGTH))
+    }
+
+    /// Session to send with
+    pub fn session(&self) -> &Session {
+        self.keys.current().unwrap()
+    }
+
+    /// Decrypt in place with whichever session the peer used, and get ready for the one after it
+    /// if the peer moved on to the next one first
+    pub fn open<'a>(
+        &mut self,
+        counter: u64,
+        ciphertext: &'a mut [u8],
+    ) -> Result<Opened<'a>, OpenError> {
+        let opened = self.keys.open(counter, ciphertext);
+        if !self.keys.has_next() {
+            self.advance_next_key();
+        }
+        opened
+    }
+
+    /// Move on to the next session without interrupting traffic, the peer keeps accepting
+    /// the current one for a while and switches too once it sees the next one or needs it itself
+    pub fn rekey_if_needed(&mut self, max_age: Duration, max_bytes: u64) {
+        if self.session().needs_rekey(max_age, max_bytes) {
+            self.keys.rotate(peer_session(
+                &self.next_key,
+                self.initiator,
+                self.session_id,
+            ));
+            self.advance_next_key();
+        }
+    }
+
+    fn advance_next_key(&mut self) {
+        self.next_key = next_peer_key(&self.next_key);
+        self.keys.set_next(peer_session(
+            &self.next_key,
+            self.initiator,
+            self.session_id,
+        ));
+    }
+
+    /// Call before every ping, a ping still waiting for its pong counts as missed
+    pub fn ping_sent(&mut self) {
+        if self.awaiting_pong {
+            self.missed_pongs += 1;
+        }
+        self.awaiting_pong = true;
+        self.ping_sent_at = Instant::now();
+    }
+
+    pub fn pong_received(&mut self) {
+        self.awaiting_pong = false;
+        self.missed_pongs = 0;
+    }
+
+    pub fn is_alive(&self) -> bool {
+        if self.confirmed {
+            self.missed_pongs < MAX_MISSED_PONGS
+        } else {
+            self.introduced_at.elapsed() < PUNCH_TIMEOUT
+        }
+    }
+
+    /// Confirmed and answering pings in time
+    fn carries_data(&self) -> bool {
+        let pong_overdue = self.awaiting_pong && self.ping_sent_at.elapsed() >= PONG_TIMEOUT;
+        self.confirmed && self.is_alive() && !pong_overdue
+    }
+}
+
+/// Direct paths by peer MAC address, with an index by session ID for received packets
+#[derive(Default)]
+pub struct PeerPaths {
+    paths: HashMap<MacAddr6, PeerPath>,
+    mac_addresses: HashMap<SessionId, MacAddr6>,
+    /// When we last asked the server for a path to a peer
+    requested_at: HashMap<MacAddr6, Instant>,
+}
+
+impl PeerPaths {
+    /// A path data to `mac_address` can be sent on
+    pub fn direct_mut(&mut self, mac_address: &MacAddr6) -> Option<&mut PeerPath> {
+        self.paths
+            .get_mut(mac_address)
+            .filter(|path| path.carries_data())
+    }
+
+    pub fn get_by_session_mut(&mut self, session_id: SessionId) -> Option<&mut PeerPath> {
+        self.paths.get_mut(self.mac_addresses.get(&session_id)?)
+    }
+
+    /// Whether to ask the server for a path to `mac_address`, records the request if so
+    pub fn should_request(&mut self, mac_address: MacAddr6, nat_type: NatType) -> bool {
+        let interval = match nat_type {
+            NatType::Symmetric => SYMMETRIC_REQUEST_INTERVAL,
+            NatType::Unknown | NatType::Cone => REQUEST_INTERVAL,
+        };
+        if self.paths.contains_key(&mac_address)
+            || self
+                .requested_at
+                .get(&mac_address)
+                .is_some_and(|requested_at| requested_at.elapsed() < interval)
+        {
+            return false;
+        }
+        self.requested_at.insert(mac_address, Instant::now());
+        true
+    }
+
+    /// Replaces the path to the same peer
+    pub fn insert(&mut self, path: PeerPath) {
+        if let Some(old_path) = self.paths.remove(&path.mac_address) {
+            self.mac_addresses.remove(&old_path.session_id);
+        }
+        self.mac_addresses.insert(path.session_id, path.mac_address);
+        self.paths.insert(path.mac_address, path);
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut PeerPath> {
+        self.paths.values_mut()
+    }
+
+    /// Paths data can be sent on
+    pub fn direct_values_mut(&mut self) -> impl Iterator<Item = &mut PeerPath> {
+        self.paths.values_mut().filter(|path| path.carries_data())
+    }
+
+    /// Drop the paths `should_keep` says no to, traffic to those peers goes through the relay
+    pub fn retain(&mut self, mut should_keep: impl FnMut(&PeerPath) -> bool) {
+        let mac_addresses = &mut self.mac_addresses;
+        self.paths.retain(|_, path| {
+            let keep = should_keep(path);
+            if !keep {
+                mac_addresses.remove(&path.session_id);
+            }
+            keep
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC_ADDRESS: MacAddr6 = MacAddr6::new(2, 0, 0, 0, 0, 1);
+
+    fn confirmed_paths() -> PeerPaths {
+        let endpoint = "203.0.113.1:4000".parse().unwrap();
+        let mut path = PeerPath::new(MAC_ADDRESS, endpoint, &[7; 32], true, 1);
+        path.confirmed = true;
+        let mut paths = PeerPaths::default();
+        paths.insert(path);
+        paths
+    }
+
+    /// As if the last ping went out `elapsed` ago
+    fn ping_sent_before(paths: &mut PeerPaths, elapsed: Duration) {
+        let path = paths.values_mut().next().unwrap();
+        path.ping_sent();
+        path.ping_sent_at -= elapsed;
+    }
+
+    #[test]
+    fn data_goes_through_the_relay_while_a_pong_is_late() {
+        let mut paths = confirmed_paths();
+        assert!(paths.direct_mut(&MAC_ADDRESS).is_some());
+        ping_sent_before(&mut paths, Duration::ZERO);
+        assert!(paths.direct_mut(&MAC_ADDRESS).is_some());
+        ping_sent_before(&mut paths, PONG_TIMEOUT);
+        assert!(paths.direct_mut(&MAC_ADDRESS).is_none());
+        assert_eq!(paths.direct_values_mut().count(), 0);
+        // Back on the path once it answers
+        paths.values_mut().next().unwrap().pong_received();
+        assert!(paths.direct_mut(&MAC_ADDRESS).is_some());
+    }
+
+    #[test]
+    fn paths_are_dropped_after_missed_pongs() {
+        let mut paths = confirmed_paths();
+        for _ in 0..MAX_MISSED_PONGS {
+            assert!(paths.values_mut().next().unwrap().is_alive());
+            ping_sent_before(&mut paths, Duration::ZERO);
+        }
+        ping_sent_before(&mut paths, Duration::ZERO);
+        assert!(!paths.values_mut().next().unwrap().is_alive());
+    }
+}
@@ -9,6 +9,7 @@ pub trait TapDevice {
     fn up(&self) -> io::Result<()>;
     fn get_mac(&self) -> io::Result<MacAddr6>;
     fn get_mtu(&self) -> io::Result<u32>;
+    fn set_mtu(&self, mtu: u32) -> io::Result<()>;
     fn set_ip(&self, address: impl Into<Ipv4Addr>, mask: impl Into<Ipv4Addr>) -> io::Result<()>;
     fn read_non_mut(&self, buf: &mut [u8]) -> io::Result<usize>;
     fn write_non_mut(&self, buf: &[u8]) -> io::Result<usize>;
@@ -41,6 +42,26 @@ impl TapDevice for Device {
         self.0.get_mtu()
     }
 
+    fn set_mtu(&self, mtu: u32) -> io::Result<()> {
+        use std::process::Command;
+        let status = Command::new("netsh")
+            .args([
+                "interface",
+                "ipv4",
+                "set",
+                "subinterface",
+                INTERFACE_NAME,
+                &format!("mtu={mtu}"),
+                "store=active",
+            ])
+            .status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("netsh exited with {status}")))
+        }
+    }
+
     fn set_ip(&self, address: impl Into<Ipv4Addr>, mask: impl Into<Ipv4Addr>) -> io::Result<()> {
         self.0.set_ip(address, mask)
     }
@@ -96,8 +117,14 @@ impl TapDevice for Device {
     }
 
     fn get_mtu(&self) -> io::Result<u32> {
-        // TODO: actually get the mtu instead of hard code a default value
-        return Ok(1500);
+        let mtu = std::fs::read_to_string(format!("/sys/class/net/{}/mtu", self.0.name()))?;
+        mtu.trim()
+            .parse()
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    fn set_mtu(&self, mtu: u32) -> io::Result<()> {
+        ip_command(&["link", "set", "dev", self.0.name(), "mtu", &mtu.to_string()])
     }
 
     fn set_ip(&self, address: impl Into<Ipv4Addr>, mask: impl Into<Ipv4Addr>) -> io::Result<()> {
@@ -112,6 +139,8 @@ impl TapDevice for Device {
             }
         }
 
+        // Replace the IP the server gave us before, it can move us to another one
+        ip_command(&["addr", "flush", "dev", self.0.name()])?;
         ip_command(&[
             "addr",
             "add",
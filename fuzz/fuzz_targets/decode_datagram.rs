@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shared::decode_datagram;
+
+// Every received datagram goes through this before being authenticated,
+// the header and transport layout as well as bincode for handshake packets
+fuzz_target!(|payload: &[u8]| {
+    let _ = decode_datagram(payload);
+});
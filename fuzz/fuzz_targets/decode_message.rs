@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shared::Opened;
+
+// Decrypted plaintexts go through this, the fixed data layout as well as bincode
+fuzz_target!(|plaintext: &[u8]| {
+    let _ = Opened::decode(plaintext);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shared::get_mac_addresses;
+
+fuzz_target!(|ethernet_frame: &[u8]| {
+    let _ = get_mac_addresses(ethernet_frame);
+});
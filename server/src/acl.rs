@@ -0,0 +1,541 @@
+use macaddr::MacAddr6;
+use std::{fs, net::Ipv4Addr, ops::RangeInclusive};
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+const IP_PROTOCOL_ICMP: u8 = 1;
+const IP_PROTOCOL_TCP: u8 = 6;
+const IP_PROTOCOL_UDP: u8 = 17;
+const IP_PROTOCOL_ICMPV6: u8 = 58;
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+const IPV6_FRAGMENT: u8 = 44;
+const IPV6_AUTHENTICATION: u8 = 51;
+const IPV6_DESTINATION_OPTIONS: u8 = 60;
+const IPV6_MOBILITY: u8 = 135;
+const IPV6_HOST_IDENTITY: u8 = 139;
+const IPV6_SHIM6: u8 = 140;
+
+/// The parts of an ethernet frame rules can match on
+pub struct FrameInfo {
+    source_mac_address: MacAddr6,
+    destination_mac_address: MacAddr6,
+    /// Of the payload, inside any VLAN tags
+    ethertype: u16,
+    /// `None` for IP packets too short or malformed to tell
+    ip_protocol: Option<u8>,
+    /// Source and destination port, `None` for TCP and UDP packets if they can't be read,
+    /// like in fragments after the first one
+    ports: Option<(u16, u16)>,
+}
+
+impl FrameInfo {
+    pub fn parse(ethernet_frame: &[u8]) -> Option<Self> {
+        let destination_mac_address: [u8; 6] = ethernet_frame.get(0..6)?.try_into().unwrap();
+        let source_mac_address: [u8; 6] = ethernet_frame.get(6..12)?.try_into().unwrap();
+        let mut ethertype = u16::from_be_bytes(ethernet_frame.get(12..14)?.try_into().unwrap());
+        let mut payload = &ethernet_frame[14..];
+        // Look through 802.1Q and 802.1ad tags, a tag can't hide a frame from the rules
+        while matches!(ethertype, ETHERTYPE_VLAN | ETHERTYPE_QINQ) {
+            let Some(tag) = payload.get(0..4) else {
+                break;
+            };
+            ethertype = u16::from_be_bytes([tag[2], tag[3]]);
+            payload = &payload[4..];
+        }
+        let (ip_protocol, transport) = match ethertype {
+            ETHERTYPE_IPV4 => parse_ipv4(payload),
+            ETHERTYPE_IPV6 => parse_ipv6(payload),
+            _ => (None, None),
+        };
+        let ports = match (ip_protocol, transport) {
+            (Some(IP_PROTOCOL_TCP | IP_PROTOCOL_UDP), Some(transport)) if transport.len() >= 4 => {
+                Some((
+                    u16::from_be_bytes([transport[0], transport[1]]),
+                    u16::from_be_bytes([transport[2], transport[3]]),
+                ))
+            }
+            _ => None,
+        };
+        Some(Self {
+            source_mac_address: source_mac_address.into(),
+            destination_mac_address: destination_mac_address.into(),
+            ethertype,
+            ip_protocol,
+            ports,
+        })
+    }
+
+    fn is_ip(&self) -> bool {
+        matches!(self.ethertype, ETHERTYPE_IPV4 | ETHERTYPE_IPV6)
+    }
+
+    /// `None` if the packet might be TCP or UDP but its ports can't be read
+    fn port_matches(
+        &self,
+        port: impl Fn((u16, u16)) -> u16,
+        ports: &RangeInclusive<u16>,
+    ) -> Option<bool> {
+        match (self.ports, self.ip_protocol) {
+            (Some(frame_ports), _) => Some(ports.contains(&port(frame_ports))),
+            (None, Some(IP_PROTOCOL_TCP | IP_PROTOCOL_UDP)) => None,
+            (None, None) if self.is_ip() => None,
+            (None, _) => Some(false),
+        }
+    }
+}
+
+/// Protocol and transport header, which only the first fragment has
+fn parse_ipv4(packet: &[u8]) -> (Option<u8>, Option<&[u8]>) {
+    let (Some(&version_and_length), Some(&protocol)) = (packet.first(), packet.get(9)) else {
+        return (None, None);
+    };
+    let header_length = (version_and_length & 0x0f) as usize * 4;
+    if header_length < 20 {
+        return (Some(protocol), None);
+    }
+    let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+    if fragment_offset != 0 {
+        return (Some(protocol), None);
+    }
+    (Some(protocol), packet.get(header_length..))
+}
+
+/// Protocol and transport header after the extension headers,
+/// the protocol is `None` if the chain is cut short
+fn parse_ipv6(packet: &[u8]) -> (Option<u8>, Option<&[u8]>) {
+    let (Some(&first_header), Some(mut rest)) = (packet.get(6), packet.get(40..)) else {
+        return (None, None);
+    };
+    let mut next_header = first_header;
+    loop {
+        let header_length = match next_header {
+            IPV6_FRAGMENT => {
+                let Some(header) = rest.get(0..8) else {
+                    return (None, None);
+                };
+                let fragment_offset = u16::from_be_bytes([header[2], header[3]]) >> 3;
+                if fragment_offset != 0 {
+                    return (Some(header[0]), None);
+                }
+                8
+            }
+            IPV6_AUTHENTICATION => match rest.get(1) {
+                Some(&length) => (length as usize + 2) * 4,
+                None => return (None, None),
+            },
+            IPV6_HOP_BY_HOP
+            | IPV6_ROUTING
+            | IPV6_DESTINATION_OPTIONS
+            | IPV6_MOBILITY
+            | IPV6_HOST_IDENTITY
+            | IPV6_SHIM6 => match rest.get(1) {
+                Some(&length) => (length as usize + 1) * 8,
+                None => return (None, None),
+            },
+            protocol => return (Some(protocol), Some(rest)),
+        };
+        match (rest.first(), rest.get(header_length..)) {
+            (Some(&header), Some(after)) => {
+                next_header = header;
+                rest = after;
+            }
+            _ => return (None, None),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Action {
+    Allow,
+    Deny,
+}
+
+struct Subnet {
+    address: Ipv4Addr,
+    prefix_length: u32,
+}
+
+impl Subnet {
+    fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::MAX.checked_shl(32 - self.prefix_length).unwrap_or(0);
+        u32::from(ip) & mask == u32::from(self.address) & mask
+    }
+}
+
+enum Condition {
+    SourceMac(MacAddr6),
+    DestinationMac(MacAddr6),
+    /// IP the server assigned to the sender
+    SourceIp(Subnet),
+    /// IP the server assigned to the receiver
+    DestinationIp(Subnet),
+    Ethertype(u16),
+    IpProtocol(u8),
+    SourcePort(RangeInclusive<u16>),
+    DestinationPort(RangeInclusive<u16>),
+}
+
+impl Condition {
+    /// `None` if the frame is an IP packet but the part this looks at can't be read
+    fn matches(
+        &self,
+        frame: &FrameInfo,
+        source_ip: Ipv4Addr,
+        destination_ip: Ipv4Addr,
+    ) -> Option<bool> {
+        let matches = match self {
+            Condition::SourceMac(mac_address) => frame.source_mac_address == *mac_address,
+            Condition::DestinationMac(mac_address) => frame.destination_mac_address == *mac_address,
+            Condition::SourceIp(subnet) => subnet.contains(source_ip),
+            Condition::DestinationIp(subnet) => subnet.contains(destination_ip),
+            Condition::Ethertype(ethertype) => frame.ethertype == *ethertype,
+            Condition::IpProtocol(protocol) => match frame.ip_protocol {
+                Some(frame_protocol) => frame_protocol == *protocol,
+                None if frame.is_ip() => return None,
+                None => false,
+            },
+            Condition::SourcePort(ports) => frame.port_matches(|(port, _)| port, ports)?,
+            Condition::DestinationPort(ports) => frame.port_matches(|(_, port)| port, ports)?,
+        };
+        Some(matches)
+    }
+}
+
+struct Rule {
+    action: Action,
+    conditions: Vec<Condition>,
+}
+
+/// Allow and deny rules for forwarding frames between clients, the first matching rule wins,
+/// frames matching no rule are allowed
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content =
+            fs::read_to_string(path).map_err(|error| format!("Can't read {path}: {error}"))?;
+        Self::parse(&content).map_err(|error| format!("{path}: {error}"))
+    }
+
+    /// One `<allow|deny> [condition=value ...]` rule per line,
+    /// empty lines and everything after `#` are ignored
+    fn parse(content: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let rule = parse_rule(line).map_err(|error| format!("line {}: {error}", index + 1))?;
+            rules.push(rule);
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// `source_ip` and `destination_ip` are the IPs assigned to the sender and the receiver,
+    /// conditions on what can't be read from a frame match deny rules but not allow rules
+    /// so fragments and malformed packets can't get around them
+    pub fn allows(&self, frame: &FrameInfo, source_ip: Ipv4Addr, destination_ip: Ipv4Addr) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| {
+                rule.conditions.iter().all(|condition| {
+                    condition
+                        .matches(frame, source_ip, destination_ip)
+                        .unwrap_or(rule.action == Action::Deny)
+                })
+            })
+            .is_none_or(|rule| rule.action == Action::Allow)
+    }
+}
+
+fn parse_rule(line: &str) -> Result<Rule, String> {
+    let mut words = line.split_whitespace();
+    let action = match words.next() {
+        Some("allow") => Action::Allow,
+        Some("deny") => Action::Deny,
+        Some(other) => return Err(format!("unknown action `{other}`, expected allow or deny")),
+        None => unreachable!(),
+    };
+    let conditions = words.map(parse_condition).collect::<Result<Vec<_>, _>>()?;
+    Ok(Rule { action, conditions })
+}
+
+fn parse_condition(word: &str) -> Result<Condition, String> {
+    let (key, value) = word
+        .split_once('=')
+        .ok_or_else(|| format!("expected `key=value`, got `{word}`"))?;
+    let invalid = || format!("invalid value for {key}: `{value}`");
+    let condition = match key {
+        "src-mac" => Condition::SourceMac(value.parse().map_err(|_| invalid())?),
+        "dst-mac" => Condition::DestinationMac(value.parse().map_err(|_| invalid())?),
+        "src-ip" => Condition::SourceIp(parse_subnet(value).ok_or_else(invalid)?),
+        "dst-ip" => Condition::DestinationIp(parse_subnet(value).ok_or_else(invalid)?),
+        "ethertype" => Condition::Ethertype(parse_ethertype(value).ok_or_else(invalid)?),
+        "proto" => Condition::IpProtocol(parse_ip_protocol(value).ok_or_else(invalid)?),
+        "src-port" => Condition::SourcePort(parse_port_range(value).ok_or_else(invalid)?),
+        "dst-port" => Condition::DestinationPort(parse_port_range(value).ok_or_else(invalid)?),
+        _ => return Err(format!("unknown condition `{key}`")),
+    };
+    Ok(condition)
+}
+
+/// `10.123.123.5` or `10.123.123.0/24`
+fn parse_subnet(value: &str) -> Option<Subnet> {
+    let (address, prefix_length) = match value.split_once('/') {
+        Some((address, prefix_length)) => (address, prefix_length.parse().ok()?),
+        None => (value, 32),
+    };
+    if prefix_length > 32 {
+        return None;
+    }
+    Some(Subnet {
+        address: address.parse().ok()?,
+        prefix_length,
+    })
+}
+
+fn parse_ethertype(value: &str) -> Option<u16> {
+    match value {
+        "ipv4" => Some(ETHERTYPE_IPV4),
+        "ipv6" => Some(ETHERTYPE_IPV6),
+        "arp" => Some(ETHERTYPE_ARP),
+        _ => u16::from_str_radix(value.strip_prefix("0x")?, 16).ok(),
+    }
+}
+
+fn parse_ip_protocol(value: &str) -> Option<u8> {
+    match value {
+        "icmp" => Some(IP_PROTOCOL_ICMP),
+        "tcp" => Some(IP_PROTOCOL_TCP),
+        "udp" => Some(IP_PROTOCOL_UDP),
+        "icmpv6" => Some(IP_PROTOCOL_ICMPV6),
+        _ => value.parse().ok(),
+    }
+}
+
+/// `445` or `137-139`
+fn parse_port_range(value: &str) -> Option<RangeInclusive<u16>> {
+    match value.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+            // A reversed range would never match anything
+            (start <= end).then_some(start..=end)
+        }
+        None => {
+            let port = value.parse().ok()?;
+            Some(port..=port)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE_IP: Ipv4Addr = Ipv4Addr::new(10, 123, 123, 2);
+    const DESTINATION_IP: Ipv4Addr = Ipv4Addr::new(10, 123, 123, 3);
+
+    fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![2, 0, 0, 0, 0, 3, 2, 0, 0, 0, 0, 2];
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn vlan(tags: &[u16], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut tagged = Vec::new();
+        for (index, &tag_ethertype) in tags.iter().enumerate().skip(1) {
+            tagged.extend_from_slice(&(index as u16).to_be_bytes());
+            tagged.extend_from_slice(&tag_ethertype.to_be_bytes());
+        }
+        tagged.extend_from_slice(&100u16.to_be_bytes());
+        tagged.extend_from_slice(&ethertype.to_be_bytes());
+        tagged.extend_from_slice(payload);
+        ethernet(tags[0], &tagged)
+    }
+
+    fn ports(source_port: u16, destination_port: u16) -> Vec<u8> {
+        let mut transport = source_port.to_be_bytes().to_vec();
+        transport.extend_from_slice(&destination_port.to_be_bytes());
+        transport.extend_from_slice(&[0; 16]);
+        transport
+    }
+
+    fn ipv4(protocol: u8, fragment_offset: u16, transport: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(&fragment_offset.to_be_bytes());
+        packet.extend_from_slice(&[64, protocol, 0, 0]);
+        packet.extend_from_slice(&[10, 123, 123, 2, 10, 123, 123, 3]);
+        packet.extend_from_slice(transport);
+        packet
+    }
+
+    /// `extension_headers` are next header and the rest of the header after it
+    fn ipv6(first_header: u8, extension_headers: &[(u8, Vec<u8>)], transport: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0, 0, 0, first_header, 64];
+        packet.extend_from_slice(&[0; 32]);
+        for (next_header, header) in extension_headers {
+            packet.push(*next_header);
+            packet.extend_from_slice(header);
+        }
+        packet.extend_from_slice(transport);
+        packet
+    }
+
+    fn smb_acl() -> Acl {
+        Acl::parse(
+            "# Everyone can reach the game server
+            allow dst-ip=10.123.123.10
+            allow proto=tcp dst-port=80
+            deny proto=tcp dst-port=445
+            deny proto=udp dst-port=137-138",
+        )
+        .unwrap()
+    }
+
+    fn allowed(acl: &Acl, frame: &[u8]) -> bool {
+        acl.allows(&FrameInfo::parse(frame).unwrap(), SOURCE_IP, DESTINATION_IP)
+    }
+
+    #[test]
+    fn parses_rules() {
+        assert_eq!(smb_acl().len(), 4);
+        assert!(Acl::parse("deny proto=tcp dst-port=445\n\n# comment").is_ok());
+        assert!(Acl::parse("block proto=tcp").is_err());
+        assert!(Acl::parse("deny proto=tcp dst-port=x").is_err());
+        assert!(Acl::parse("deny proto=udp dst-port=137-138").is_ok());
+        assert!(Acl::parse("deny proto=udp dst-port=138-138").is_ok());
+        assert!(Acl::parse("deny proto=udp dst-port=200-100").is_err());
+        assert!(Acl::parse("deny src-ip=10.0.0.0/33").is_err());
+        assert!(Acl::parse("deny color=red").is_err());
+        assert!(Acl::parse("deny ethertype=ipx").is_err());
+    }
+
+    #[test]
+    fn parses_ipv4_ports() {
+        let frame = ethernet(
+            ETHERTYPE_IPV4,
+            &ipv4(IP_PROTOCOL_TCP, 0, &ports(50000, 445)),
+        );
+        let frame_info = FrameInfo::parse(&frame).unwrap();
+        assert_eq!(frame_info.ethertype, ETHERTYPE_IPV4);
+        assert_eq!(frame_info.ip_protocol, Some(IP_PROTOCOL_TCP));
+        assert_eq!(frame_info.ports, Some((50000, 445)));
+        assert!(FrameInfo::parse(&frame[..13]).is_none());
+    }
+
+    #[test]
+    fn follows_ipv6_extension_headers() {
+        let hop_by_hop = (IPV6_DESTINATION_OPTIONS, vec![0; 7]);
+        let destination_options = (
+            IPV6_FRAGMENT,
+            vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        );
+        let first_fragment = (IP_PROTOCOL_UDP, vec![0, 0, 1, 0, 0, 0, 1]);
+        let frame = ethernet(
+            ETHERTYPE_IPV6,
+            &ipv6(
+                IPV6_HOP_BY_HOP,
+                &[hop_by_hop, destination_options, first_fragment],
+                &ports(137, 138),
+            ),
+        );
+        let frame_info = FrameInfo::parse(&frame).unwrap();
+        assert_eq!(frame_info.ip_protocol, Some(IP_PROTOCOL_UDP));
+        assert_eq!(frame_info.ports, Some((137, 138)));
+    }
+
+    #[test]
+    fn looks_through_vlan_tags() {
+        let packet = ipv4(IP_PROTOCOL_TCP, 0, &ports(50000, 445));
+        for frame in [
+            vlan(&[ETHERTYPE_VLAN], ETHERTYPE_IPV4, &packet),
+            vlan(&[ETHERTYPE_QINQ, ETHERTYPE_VLAN], ETHERTYPE_IPV4, &packet),
+        ] {
+            let frame_info = FrameInfo::parse(&frame).unwrap();
+            assert_eq!(frame_info.ethertype, ETHERTYPE_IPV4);
+            assert_eq!(frame_info.ports, Some((50000, 445)));
+            assert!(!allowed(&smb_acl(), &frame));
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let acl = smb_acl();
+        let smb = ethernet(
+            ETHERTYPE_IPV4,
+            &ipv4(IP_PROTOCOL_TCP, 0, &ports(50000, 445)),
+        );
+        let web = ethernet(ETHERTYPE_IPV4, &ipv4(IP_PROTOCOL_TCP, 0, &ports(50000, 80)));
+        let netbios = ethernet(ETHERTYPE_IPV4, &ipv4(IP_PROTOCOL_UDP, 0, &ports(137, 137)));
+        let dns = ethernet(ETHERTYPE_IPV4, &ipv4(IP_PROTOCOL_UDP, 0, &ports(50000, 53)));
+        let arp = ethernet(ETHERTYPE_ARP, &[0; 28]);
+        assert!(!allowed(&acl, &smb));
+        assert!(allowed(&acl, &web));
+        assert!(!allowed(&acl, &netbios));
+        assert!(allowed(&acl, &dns));
+        assert!(allowed(&acl, &arp));
+        // To the game server, allowed before the deny rules
+        let frame_info = FrameInfo::parse(&smb).unwrap();
+        assert!(acl.allows(&frame_info, SOURCE_IP, Ipv4Addr::new(10, 123, 123, 10)));
+    }
+
+    #[test]
+    fn unreadable_ports_match_deny_rules_only() {
+        let acl = smb_acl();
+        let later_fragment = ethernet(ETHERTYPE_IPV4, &ipv4(IP_PROTOCOL_TCP, 100, &[0; 20]));
+        let truncated = ethernet(ETHERTYPE_IPV4, &ipv4(IP_PROTOCOL_TCP, 0, &[0x01, 0xbd]));
+        let mut bad_header_length = ipv4(IP_PROTOCOL_TCP, 0, &ports(50000, 445));
+        bad_header_length[0] = 0x44;
+        let bad_header_length = ethernet(ETHERTYPE_IPV4, &bad_header_length);
+        let mut too_long_header = ipv4(IP_PROTOCOL_TCP, 0, &ports(50000, 445));
+        too_long_header[0] = 0x4f;
+        let too_long_header = ethernet(ETHERTYPE_IPV4, &too_long_header);
+        let ipv6_later_fragment = ethernet(
+            ETHERTYPE_IPV6,
+            &ipv6(
+                IPV6_FRAGMENT,
+                &[(IP_PROTOCOL_TCP, vec![0, 0, 8, 0, 0, 0, 1])],
+                &[0; 20],
+            ),
+        );
+        let ipv6_cut_short = ethernet(
+            ETHERTYPE_IPV6,
+            &ipv6(IPV6_HOP_BY_HOP, &[(IP_PROTOCOL_TCP, vec![4])], &[]),
+        );
+        for frame in [
+            later_fragment,
+            truncated,
+            bad_header_length,
+            too_long_header,
+            ipv6_later_fragment,
+            ipv6_cut_short,
+        ] {
+            assert_eq!(FrameInfo::parse(&frame).unwrap().ports, None);
+            assert!(!allowed(&acl, &frame));
+        }
+        // Other protocols don't have ports, so port rules never match them
+        let icmp = ethernet(ETHERTYPE_IPV4, &ipv4(IP_PROTOCOL_ICMP, 100, &[0; 8]));
+        assert!(allowed(&acl, &icmp));
+    }
+
+    #[test]
+    fn unreadable_ports_never_match_allow_rules() {
+        let acl = Acl::parse("allow proto=tcp dst-port=80\ndeny").unwrap();
+        let web = ethernet(ETHERTYPE_IPV4, &ipv4(IP_PROTOCOL_TCP, 0, &ports(50000, 80)));
+        let later_fragment = ethernet(ETHERTYPE_IPV4, &ipv4(IP_PROTOCOL_TCP, 100, &[0; 20]));
+        assert!(allowed(&acl, &web));
+        assert!(!allowed(&acl, &later_fragment));
+    }
+}
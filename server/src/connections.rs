@@ -0,0 +1,70 @@
+use crate::rate_limit::RateLimits;
+use macaddr::MacAddr6;
+use shared::SessionId;
+use std::{collections::HashMap, net::Ipv4Addr, time::Instant};
+
+pub struct Connection {
+    pub ip: Ipv4Addr,
+    pub mac_address: MacAddr6,
+    /// The session that registered this connection, only frames from it can use the MAC address
+    pub session_id: SessionId,
+    /// Public key from the identities file the client registered with
+    pub identity: Option<[u8; 32]>,
+    /// Follows the MAC address, so reconnecting with a new session doesn't reset them
+    pub rate_limits: RateLimits,
+    pub last_seen: Instant,
+}
+
+/// Registered clients by session ID, with an index by MAC address for forwarding frames
+#[derive(Default)]
+pub struct Connections {
+    connections: HashMap<SessionId, Connection>,
+    session_ids: HashMap<MacAddr6, SessionId>,
+}
+
+impl Connections {
+    pub fn get(&self, session_id: SessionId) -> Option<&Connection> {
+        self.connections.get(&session_id)
+    }
+
+    pub fn get_mut(&mut self, session_id: SessionId) -> Option<&mut Connection> {
+        self.connections.get_mut(&session_id)
+    }
+
+    pub fn get_by_mac_address(&self, mac_address: &MacAddr6) -> Option<&Connection> {
+        self.get(*self.session_ids.get(mac_address)?)
+    }
+
+    /// Remove the connections of the same session and MAC address first
+    pub fn insert(&mut self, connection: Connection) {
+        self.session_ids
+            .insert(connection.mac_address, connection.session_id);
+        self.connections.insert(connection.session_id, connection);
+    }
+
+    pub fn remove(&mut self, session_id: SessionId) -> Option<Connection> {
+        let connection = self.connections.remove(&session_id)?;
+        self.session_ids.remove(&connection.mac_address);
+        Some(connection)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Connection> {
+        self.connections.values()
+    }
+
+    /// Don't change the MAC address, it's indexed
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut Connection> {
+        self.connections.values_mut()
+    }
+
+    pub fn retain(&mut self, mut should_keep: impl FnMut(&Connection) -> bool) {
+        let session_ids = &mut self.session_ids;
+        self.connections.retain(|_, connection| {
+            let keep = should_keep(connection);
+            if !keep {
+                session_ids.remove(&connection.mac_address);
+            }
+            keep
+        });
+    }
+}
@@ -0,0 +1,149 @@
+use std::{collections::HashMap, fs, net::Ipv4Addr, time::SystemTime};
+
+/// Public keys of the clients allowed to join, reloaded when the file changes
+/// so an identity can be revoked without restarting the server
+pub struct Identities {
+    path: String,
+    modified: Option<SystemTime>,
+    identities: HashMap<[u8; 32], Identity>,
+}
+
+struct Identity {
+    name: String,
+    /// Always assigned to this identity if it's free
+    ip: Option<Ipv4Addr>,
+}
+
+impl Identities {
+    pub fn load(path: String) -> Result<Self, String> {
+        let mut identities = Self {
+            path,
+            modified: None,
+            identities: HashMap::new(),
+        };
+        identities.reload_if_changed()?;
+        Ok(identities)
+    }
+
+    pub fn name(&self, public_key: &[u8; 32]) -> Option<&str> {
+        self.identities
+            .get(public_key)
+            .map(|identity| identity.name.as_str())
+    }
+
+    pub fn ip(&self, public_key: &[u8; 32]) -> Option<Ipv4Addr> {
+        self.identities.get(public_key)?.ip
+    }
+
+    pub fn contains(&self, public_key: &[u8; 32]) -> bool {
+        self.identities.contains_key(public_key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.identities.len()
+    }
+
+    /// Returns true if the file changed and got reloaded,
+    /// the old identities are kept if the new file can't be parsed
+    pub fn reload_if_changed(&mut self) -> Result<bool, String> {
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|error| format!("Can't read {}: {error}", self.path))?;
+        if self.modified == Some(modified) {
+            return Ok(false);
+        }
+        let content = fs::read_to_string(&self.path)
+            .map_err(|error| format!("Can't read {}: {error}", self.path))?;
+        self.identities = parse(&content).map_err(|error| format!("{}: {error}", self.path))?;
+        self.modified = Some(modified);
+        Ok(true)
+    }
+}
+
+/// One `<name> <hex public key> [ip]` per line, empty lines and lines starting with `#` are ignored
+fn parse(content: &str) -> Result<HashMap<[u8; 32], Identity>, String> {
+    let mut identities = HashMap::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line_number = index + 1;
+        let words: Vec<_> = line.split_whitespace().collect();
+        let (name, public_key, ip) = match words[..] {
+            [name, public_key] => (name, public_key, None),
+            [name, public_key, ip] => (name, public_key, Some(ip)),
+            _ => {
+                return Err(format!(
+                    "line {line_number}: expected `<name> <public key> [ip]`"
+                ))
+            }
+        };
+        let public_key: [u8; 32] = hex::decode(public_key)
+            .ok()
+            .and_then(|public_key| public_key.try_into().ok())
+            .ok_or_else(|| format!("line {line_number}: invalid public key"))?;
+        let ip = ip
+            .map(|ip| ip.parse())
+            .transpose()
+            .map_err(|_| format!("line {line_number}: invalid IP"))?;
+        if identities.contains_key(&public_key) {
+            return Err(format!("line {line_number}: public key listed twice"));
+        }
+        identities.insert(
+            public_key,
+            Identity {
+                name: name.to_owned(),
+                ip,
+            },
+        );
+    }
+    Ok(identities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "3b6a27bcceb6a42d62a3a8d02a6f0d73653215771de243a63ac048a18b59da29";
+    const BOB: &str = "0f6a0b8c2a6cf1f4a9dbe4e5f4a0c9d3e8b1a2c4d6e8f0a1b3c5d7e9f1a3b5c7";
+
+    fn public_key(hex_public_key: &str) -> [u8; 32] {
+        hex::decode(hex_public_key).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn names_and_fixed_ips_are_read() {
+        let content = format!("# comment\n\nalice {ALICE} 10.123.123.10\n  bob {BOB}  \n");
+        let identities = parse(&content).unwrap();
+        assert_eq!(identities.len(), 2);
+        let alice = &identities[&public_key(ALICE)];
+        assert_eq!(alice.name, "alice");
+        assert_eq!(alice.ip, Some(Ipv4Addr::new(10, 123, 123, 10)));
+        assert_eq!(identities[&public_key(BOB)].ip, None);
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        for content in [
+            "alice".to_owned(),
+            format!("alice {ALICE} 10.123.123.10 extra"),
+            "alice not-hex".to_owned(),
+            format!("alice {}", &ALICE[..62]),
+            format!("alice {ALICE}00"),
+            format!("alice {ALICE} 10.123.123.300"),
+            format!("alice {ALICE} ::1"),
+        ] {
+            assert!(parse(&content).is_err(), "{content}");
+        }
+    }
+
+    #[test]
+    fn duplicate_public_keys_are_rejected() {
+        let content = format!("alice {ALICE}\nbob {BOB}\nmallory {ALICE}\n");
+        assert_eq!(
+            parse(&content).err().as_deref(),
+            Some("line 3: public key listed twice")
+        );
+    }
+}
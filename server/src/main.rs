@@ -1,34 +1,159 @@
+mod acl;
+mod connections;
+mod identities;
+mod rate_limit;
+
+use acl::{Acl, FrameInfo};
 use argh::FromArgs;
+use connections::{Connection, Connections};
+use identities::Identities;
 use macaddr::MacAddr6;
+use rate_limit::{too_many_tunnels, HandshakeLimits, RateLimits};
 use shared::{
-    get_formatted_time, get_mac_addresses, log, receive_until_success, send_to,
-    setup_panic_logging_hook, Message, ReceiveMessage,
+    auth::{generate_nonce, verify_identity_proof, verify_register_proof, Proof},
+    compression::{decompress, encode_compressed, CompressionStats},
+    cookie::CookieGenerator,
+    crypto::{generate_peer_key, generate_session_id, respond, HandshakeResponse, StaticKey},
+    encode_message,
+    fragment::{encode_frame, Reassembler},
+    get_formatted_time, get_mac_addresses,
+    key_ring::KeyRing,
+    log,
+    peer_list::{encode_peer_list, PeerInfo},
+    pmtu::probe_datagram_length,
+    receive_until_success, send_data_plaintext_to, send_packet_to, send_plaintext_to,
+    sequence::SequenceTracker,
+    setup_panic_logging_hook, Datagram, DecodeErrors, Message, OpenError, Opened, Packet,
+    ReceivePacket, SessionId, CAPABILITIES, CAPABILITY_COMPRESSION, CAPABILITY_DIRECT,
+    CAPABILITY_PEER_LIST, PROTOCOL_VERSION, RECEIVE_BUFFER_SIZE,
 };
 use socket2::{Domain, Socket, Type};
 use std::{
+    cell::OnceCell,
     collections::{HashMap, HashSet},
+    fs,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
-    sync::Mutex,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Mutex,
+    },
     thread::{self, sleep},
     time::{Duration, Instant},
 };
 
 const SUBNET: Ipv4Addr = Ipv4Addr::new(10, 123, 123, 0);
 const SUBNET_MASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(30);
+const SESSION_TIMEOUT: Duration = Duration::from_secs(200);
+/// Clients ping every 5 seconds, missing 3 pings in a row means it's gone
+/// and someone else can register with its MAC address
+const OWNER_GONE_TIMEOUT: Duration = Duration::from_secs(15);
 
-struct Connection {
-    ip: Ipv4Addr,
+/// Who a verified register request came from
+struct Registration {
     mac_address: MacAddr6,
+    identity: Option<[u8; 32]>,
+}
+
+/// A register request waiting for the client to answer our challenge
+struct PendingChallenge {
+    nonce: [u8; 32],
+    mac_address: MacAddr6,
+    created_at: Instant,
+}
+
+/// Encryption keys of a session that finished the handshake
+struct Tunnel {
+    keys: KeyRing,
+    /// Where the last authenticated packet came from, the session moves with the client
     socket_address: SocketAddr,
+    /// Frames the client sent in fragments
+    reassembler: Reassembler,
+    /// Capabilities both sides support, set once the client said hello
+    capabilities: Option<u32>,
+    /// Data frames sent to and received from the client, if it negotiated compression
+    compression_stats: CompressionStats,
+    /// Sequence number of the next data message to the client
+    next_sequence: u64,
+    /// Data messages from the client
+    received_sequences: SequenceTracker,
+    rate_limits: RateLimits,
     last_seen: Instant,
 }
 
+impl Tunnel {
+    fn has_capability(&self, capability: u32) -> bool {
+        self.capabilities
+            .is_some_and(|capabilities| capabilities & capability != 0)
+    }
+
+    fn compresses(&self) -> bool {
+        self.has_capability(CAPABILITY_COMPRESSION)
+    }
+}
+
+struct State {
+    network_key: Option<String>,
+    identities: Option<Mutex<Identities>>,
+    acl: Option<Acl>,
+    /// Bytes per second of data each session and each connection can send
+    max_data_rate: u64,
+    /// Told to clients asking for their address
+    probe_port: Option<u16>,
+    static_key: StaticKey,
+    cookie_generator: CookieGenerator,
+    ip_pool: Mutex<HashSet<Ipv4Addr>>,
+    connections: Mutex<Connections>,
+    pending_challenges: Mutex<HashMap<SessionId, PendingChallenge>>,
+    tunnels: Mutex<HashMap<SessionId, Tunnel>>,
+    handshake_limits: Mutex<HandshakeLimits>,
+    /// Of the last peer list pushed to clients
+    peer_list_generation: AtomicU64,
+    decode_errors: DecodeErrors,
+    /// Answered with our version instead of a handshake
+    other_version_handshakes: AtomicU64,
+    decryption_failures: AtomicU64,
+    replayed_packets: AtomicU64,
+    /// Decrypted but not a message we can decode or take
+    undecodable_messages: AtomicU64,
+    /// Valid but only ever sent by the server
+    unexpected_packets: AtomicU64,
+    unexpected_messages: AtomicU64,
+    spoofed_frames: AtomicU64,
+    denied_frames: AtomicU64,
+    rate_limited_control: AtomicU64,
+    rate_limited_data: AtomicU64,
+    /// Over the handshake rate or the sessions an IP can have
+    rate_limited_handshakes: AtomicU64,
+}
+
 /// A simple peer to peer VPN client
 #[derive(FromArgs)]
 struct Cli {
     /// listening port
     #[argh(positional)]
     port: u16,
+    /// secret key clients need to know to join the network
+    #[argh(option)]
+    network_key: Option<String>,
+    /// file with the public keys of clients allowed to join, one `<name> <public key>` per line,
+    /// reloaded when it changes
+    #[argh(option)]
+    identities: Option<String>,
+    /// file with allow and deny rules for frames forwarded between clients,
+    /// one `<allow|deny> [condition=value ...]` rule per line
+    #[argh(option)]
+    acl: Option<String>,
+    /// bytes per second of ethernet frames each client can send, 12500000 (100 Mbit/s) by default
+    #[argh(option, default = "12_500_000")]
+    max_data_rate: u64,
+    /// file to load the server's private key from, generated if it doesn't exist
+    #[argh(option, default = "String::from(\"server.key\")")]
+    key_file: String,
+    /// second port clients ask for their address on to tell their NAT type,
+    /// only address requests are answered on it
+    #[argh(option)]
+    probe_port: Option<u16>,
 }
 
 fn main() {
@@ -36,37 +161,146 @@ fn main() {
 
     setup_panic_logging_hook();
 
-    let ip_pool: Mutex<HashSet<Ipv4Addr>> = Mutex::new(generate_ip_pool());
-    let connections: Mutex<HashMap<MacAddr6, Connection>> = Mutex::new(HashMap::new());
+    if config.network_key.is_none() && config.identities.is_none() {
+        panic!("Either --network-key or --identities is needed for clients to join");
+    }
+    let identities = config.identities.map(|path| {
+        let identities = Identities::load(path).unwrap_or_else(|error| panic!("{error}"));
+        log!("Loaded {} identities", identities.len());
+        Mutex::new(identities)
+    });
+    let acl = config.acl.map(|path| {
+        let acl = Acl::load(&path).unwrap_or_else(|error| panic!("{error}"));
+        log!("Loaded {} ACL rules", acl.len());
+        acl
+    });
+
+    let static_key = load_or_generate_static_key(&config.key_file);
+    log!(
+        "Server public key: {}",
+        hex::encode(static_key.public_key())
+    );
 
-    thread::scope(|scope| {
-        scope.spawn(|| {
-            let socket = Socket::new(Domain::IPV6, Type::DGRAM, None).expect("Can't create socket");
-            socket
-                .set_only_v6(false)
-                .expect("Can't set socket to receive packets from an IPv4-mapped IPv6 address");
+    let state = &State {
+        network_key: config.network_key,
+        identities,
+        acl,
+        max_data_rate: config.max_data_rate,
+        probe_port: config.probe_port,
+        static_key,
+        cookie_generator: CookieGenerator::new(),
+        ip_pool: Mutex::new(generate_ip_pool()),
+        connections: Mutex::new(Connections::default()),
+        pending_challenges: Mutex::new(HashMap::new()),
+        tunnels: Mutex::new(HashMap::new()),
+        handshake_limits: Mutex::new(HandshakeLimits::default()),
+        peer_list_generation: AtomicU64::new(0),
+        decode_errors: DecodeErrors::default(),
+        other_version_handshakes: AtomicU64::new(0),
+        decryption_failures: AtomicU64::new(0),
+        replayed_packets: AtomicU64::new(0),
+        undecodable_messages: AtomicU64::new(0),
+        unexpected_packets: AtomicU64::new(0),
+        unexpected_messages: AtomicU64::new(0),
+        spoofed_frames: AtomicU64::new(0),
+        denied_frames: AtomicU64::new(0),
+        rate_limited_control: AtomicU64::new(0),
+        rate_limited_handshakes: AtomicU64::new(0),
+        rate_limited_data: AtomicU64::new(0),
+    };
 
-            let address = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), config.port);
-            socket
-                .bind(&address.into())
-                .expect(&format!("Can't bind to address {address}"));
-            let socket: UdpSocket = socket.into();
+    let socket = &setup_socket(config.port);
+    log!("Server listening at [::]:{}", config.port);
+    let probe_socket = &config.probe_port.map(|probe_port| {
+        let probe_socket = setup_socket(probe_port);
+        log!("Answering address requests at [::]:{probe_port}");
+        probe_socket
+    });
 
-            log!("Server listening at [::]:{}", config.port);
+    let (shutdown_sender, shutdown_receiver) = mpsc::channel();
+    // Ctrl-C or SIGTERM
+    ctrlc::set_handler(move || {
+        let _ = shutdown_sender.send(());
+    })
+    .expect("Can't set shutdown handler");
 
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut buffer = vec![0; RECEIVE_BUFFER_SIZE];
             loop {
-                handle_message(&socket, &connections, &ip_pool);
+                handle_packet(socket, state, &mut buffer);
             }
         });
 
+        if let Some(probe_socket) = probe_socket {
+            scope.spawn(|| {
+                let mut buffer = vec![0; RECEIVE_BUFFER_SIZE];
+                loop {
+                    handle_probe_packet(probe_socket, state, &mut buffer);
+                }
+            });
+        }
+
+        scope.spawn(move || {
+            shutdown_receiver.recv().unwrap();
+            shutdown(state, socket);
+        });
+
+        if state.identities.is_some() {
+            scope.spawn(|| loop {
+                sleep(Duration::from_secs(5));
+                reload_identities(state, socket);
+            });
+        }
+
         // Purge timed out connections
         scope.spawn(|| loop {
             sleep(Duration::from_secs(100));
-            purge_timedout_connections(&connections, &ip_pool);
+            purge_timedout_connections(state);
+            purge_timedout_challenges(state);
+            purge_timedout_tunnels(state);
+            state.handshake_limits.lock().unwrap().purge_full();
+            // Also catches up clients that lost a page
+            push_peer_list(state, socket);
+            log_dropped_packets(state);
+            log_tunnel_stats(state);
         });
     });
 }
 
+fn setup_socket(port: u16) -> UdpSocket {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, None).expect("Can't create socket");
+    socket
+        .set_only_v6(false)
+        .expect("Can't set socket to receive packets from an IPv4-mapped IPv6 address");
+
+    let address = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
+    socket
+        .bind(&address.into())
+        .unwrap_or_else(|_| panic!("Can't bind to address {address}"));
+    socket.into()
+}
+
+fn load_or_generate_static_key(key_file: &str) -> StaticKey {
+    match fs::read_to_string(key_file) {
+        Ok(hex_secret) => StaticKey::from_hex(&hex_secret)
+            .unwrap_or_else(|| panic!("Invalid private key in {key_file}")),
+        Err(_) => {
+            let static_key = StaticKey::generate();
+            fs::write(key_file, static_key.to_hex())
+                .unwrap_or_else(|error| panic!("Can't write private key to {key_file}: {error}"));
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(key_file, fs::Permissions::from_mode(0o600))
+                    .unwrap_or_else(|error| panic!("Can't protect {key_file}: {error}"));
+            }
+            log!("Generated a new private key to {key_file}");
+            static_key
+        }
+    }
+}
+
 fn generate_ip_pool() -> HashSet<Ipv4Addr> {
     let mut ip_pool: HashSet<Ipv4Addr> = HashSet::new();
     for i in 0..=255 {
@@ -81,157 +315,1227 @@ fn get_ip(ip_pool: &Mutex<HashSet<Ipv4Addr>>) -> Option<Ipv4Addr> {
     ip_pool.lock().unwrap().iter().next().cloned()
 }
 
-// Reassign ip if it's a reconnection
-fn reassign_ip(
-    connections: &Mutex<HashMap<MacAddr6, Connection>>,
+/// Returns false if it's already assigned or outside the subnet
+fn take_ip(ip_pool: &Mutex<HashSet<Ipv4Addr>>, ip: Ipv4Addr) -> bool {
+    ip_pool.lock().unwrap().remove(&ip)
+}
+
+/// IP the identities file gives this identity
+fn fixed_ip(identity: &[u8; 32], state: &State) -> Option<Ipv4Addr> {
+    state.identities.as_ref()?.lock().unwrap().ip(identity)
+}
+
+/// Encrypt and send a message to a session that finished the handshake
+fn send_to_client(socket: &UdpSocket, state: &State, message: &Message, session_id: SessionId) {
+    send_plaintext_to_client(socket, state, &encode_message(message), session_id);
+}
+
+/// Like [`send_to_client`] with an already encoded message, so a data frame going to several
+/// clients is only encoded once
+fn send_plaintext_to_client(
+    socket: &UdpSocket,
+    state: &State,
+    plaintext: &[u8],
+    session_id: SessionId,
+) {
+    if let Some(tunnel) = state.tunnels.lock().unwrap().get(&session_id) {
+        if let Some(session) = tunnel.keys.current() {
+            send_plaintext_to(socket, session, plaintext, &tunnel.socket_address);
+        }
+    }
+}
+
+fn tunnel_socket_address(session_id: SessionId, state: &State) -> Option<SocketAddr> {
+    state
+        .tunnels
+        .lock()
+        .unwrap()
+        .get(&session_id)
+        .map(|tunnel| tunnel.socket_address)
+}
+
+/// Clients from other protocol versions never get here,
+/// their handshake is answered with a [`Packet::VersionMismatch`]
+fn hello(capabilities: u32, session_id: SessionId, state: &State, socket: &UdpSocket) {
+    let capabilities = capabilities & CAPABILITIES;
+    if let Some(tunnel) = state.tunnels.lock().unwrap().get_mut(&session_id) {
+        tunnel.capabilities = Some(capabilities);
+    }
+    send_to_client(
+        socket,
+        state,
+        &Message::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        },
+        session_id,
+    );
+}
+
+fn said_hello(session_id: SessionId, state: &State) -> bool {
+    state
+        .tunnels
+        .lock()
+        .unwrap()
+        .get(&session_id)
+        .is_some_and(|tunnel| tunnel.capabilities.is_some())
+}
+
+fn challenge(
     mac_address: MacAddr6,
+    session_id: SessionId,
     source_address: SocketAddr,
+    state: &State,
     socket: &UdpSocket,
-) -> bool {
-    let mut connections = connections.lock().unwrap();
-    match connections.get_mut(&mac_address) {
-        Some(connection) => {
-            connection.socket_address = source_address;
-            connection.last_seen = Instant::now();
-            send_to(
-                socket,
-                &Message::RegisterSuccess {
-                    ip: connection.ip,
-                    subnet_mask: SUBNET_MASK,
-                },
-                &source_address,
-            );
-            log!("Reassign IP {ip} to {source_address}", ip = connection.ip);
-            true
+) {
+    log!("Incomming client {mac_address} from {source_address}");
+
+    let nonce = generate_nonce();
+    state.pending_challenges.lock().unwrap().insert(
+        session_id,
+        PendingChallenge {
+            nonce,
+            mac_address,
+            created_at: Instant::now(),
+        },
+    );
+    send_to_client(socket, state, &Message::Challenge { nonce }, session_id);
+}
+
+/// Returns the identity used if the proof is valid
+fn check_proof(
+    proof: &Proof,
+    pending: &PendingChallenge,
+    state: &State,
+) -> Result<Option<[u8; 32]>, String> {
+    match proof {
+        Proof::NetworkKey(proof) => match &state.network_key {
+            Some(network_key) => {
+                if verify_register_proof(network_key, &pending.nonce, pending.mac_address, proof) {
+                    Ok(None)
+                } else {
+                    Err("Invalid network key".to_owned())
+                }
+            }
+            None => Err("This server only accepts identities".to_owned()),
+        },
+        Proof::Identity {
+            public_key,
+            signature,
+        } => {
+            let Some(identities) = &state.identities else {
+                return Err("This server only accepts the network key".to_owned());
+            };
+            let identities = identities.lock().unwrap();
+            let Some(name) = identities.name(public_key) else {
+                return Err(format!(
+                    "Identity {} is not allowed on this server",
+                    hex::encode(public_key)
+                ));
+            };
+            if verify_identity_proof(public_key, signature, &pending.nonce, pending.mac_address) {
+                log!("Client identified as {name}");
+                Ok(Some(*public_key))
+            } else {
+                Err(format!("Invalid signature for identity {name}"))
+            }
         }
-        None => false,
+    }
+}
+
+fn verify_challenge_response(
+    proof: Proof,
+    session_id: SessionId,
+    source_address: SocketAddr,
+    state: &State,
+    socket: &UdpSocket,
+) -> Option<Registration> {
+    let pending = state.pending_challenges.lock().unwrap().remove(&session_id);
+    let reason = match pending {
+        Some(pending) if pending.created_at.elapsed() >= CHALLENGE_TIMEOUT => {
+            "Challenge expired, register again".to_owned()
+        }
+        Some(pending) => match check_proof(&proof, &pending, state) {
+            Ok(identity) => {
+                return Some(Registration {
+                    mac_address: pending.mac_address,
+                    identity,
+                })
+            }
+            Err(reason) => reason,
+        },
+        None => "No pending challenge, register first".to_owned(),
+    };
+    log!("Rejected client from {source_address}: {reason}");
+    send_to_client(socket, state, &Message::RegisterFail { reason }, session_id);
+    None
+}
+
+/// What happens to the session registered with a MAC address when another one registers it
+#[derive(Debug, PartialEq)]
+enum Takeover {
+    /// Others can't take over a MAC address while its owner is still around
+    Refuse,
+    /// The owner coming back with the same session, a new session from the same endpoint,
+    /// or its identity from somewhere else. Its old session is dropped without a kick,
+    /// the client already moved on from it and would take the kick as meant for itself
+    Reconnect,
+    /// Someone else after the owner went quiet
+    Kick,
+}
+
+fn takeover(
+    owner: &Connection,
+    owner_address: Option<SocketAddr>,
+    registration: &Registration,
+    session_id: SessionId,
+    source_address: SocketAddr,
+) -> Takeover {
+    let is_owner = owner.session_id == session_id
+        || owner_address == Some(source_address)
+        || (registration.identity.is_some() && registration.identity == owner.identity);
+    if is_owner {
+        Takeover::Reconnect
+    } else if owner.last_seen.elapsed() < OWNER_GONE_TIMEOUT {
+        Takeover::Refuse
+    } else {
+        Takeover::Kick
     }
 }
 
 fn register(
-    mac_address: MacAddr6,
+    registration: Registration,
+    session_id: SessionId,
     source_address: SocketAddr,
-    connections: &Mutex<HashMap<MacAddr6, Connection>>,
+    state: &State,
     socket: &UdpSocket,
-    ip_pool: &Mutex<HashSet<Ipv4Addr>>,
 ) {
-    log!("Incomming client {mac_address} from {source_address}");
+    let mac_address = registration.mac_address;
+    // Looked up before locking the connections, reloading identities locks them the other way
+    let fixed_ip = registration
+        .identity
+        .and_then(|identity| fixed_ip(&identity, state));
+    let mut connections = state.connections.lock().unwrap();
+
+    // A session can only own one MAC address
+    if connections
+        .get(session_id)
+        .is_some_and(|connection| connection.mac_address != mac_address)
+    {
+        let connection = connections.remove(session_id).unwrap();
+        state.ip_pool.lock().unwrap().insert(connection.ip);
+        log!(
+            "Released {} from {source_address}, it registered with another MAC address",
+            connection.ip
+        );
+    }
+
+    let (ip, rate_limits) = match connections.get_by_mac_address(&mac_address) {
+        // Reassign ip if it's a reconnection
+        Some(connection) => {
+            let owner_address = tunnel_socket_address(connection.session_id, state);
+            let owner_session_id = connection.session_id;
+            match takeover(
+                connection,
+                owner_address,
+                &registration,
+                session_id,
+                source_address,
+            ) {
+                Takeover::Refuse => {
+                    log!(
+                        "Refused {source_address} taking over {mac_address} from {}",
+                        owner_address
+                            .map_or("a closed session".to_owned(), |address| address.to_string())
+                    );
+                    send_to_client(
+                        socket,
+                        state,
+                        &Message::RegisterFail {
+                            reason: format!("MAC address {mac_address} is used by another client"),
+                        },
+                        session_id,
+                    );
+                    return;
+                }
+                Takeover::Reconnect => {
+                    if owner_session_id != session_id {
+                        state.tunnels.lock().unwrap().remove(&owner_session_id);
+                    }
+                }
+                // Tell the old owner if it's still around somewhere else
+                Takeover::Kick => {
+                    if owner_address.is_some() {
+                        kick(
+                            owner_session_id,
+                            format!("Another client took over {mac_address}"),
+                            state,
+                            socket,
+                        );
+                    }
+                }
+            }
+            let connection = connections.remove(owner_session_id).unwrap();
+            log!("Reassign IP {ip} to {source_address}", ip = connection.ip);
+            (connection.ip, connection.rate_limits)
+        }
+        None => match get_ip(&state.ip_pool) {
+            Some(ip) => {
+                state.ip_pool.lock().unwrap().remove(&ip);
+                log!("Assign IP {ip} to {source_address}");
+                (ip, RateLimits::new(state.max_data_rate))
+            }
+            None => {
+                send_to_client(
+                    socket,
+                    state,
+                    &Message::RegisterFail {
+                        reason: "No avalible ip left".to_owned(),
+                    },
+                    session_id,
+                );
+                return;
+            }
+        },
+    };
+    let ip = match fixed_ip {
+        Some(fixed_ip) if fixed_ip != ip && take_ip(&state.ip_pool, fixed_ip) => {
+            state.ip_pool.lock().unwrap().insert(ip);
+            log!("Assign fixed IP {fixed_ip} to {source_address} instead");
+            fixed_ip
+        }
+        _ => ip,
+    };
+    connections.insert(Connection {
+        ip,
+        mac_address,
+        session_id,
+        identity: registration.identity,
+        rate_limits,
+        last_seen: Instant::now(),
+    });
+    send_to_client(
+        socket,
+        state,
+        &Message::RegisterSuccess {
+            ip,
+            subnet_mask: SUBNET_MASK,
+        },
+        session_id,
+    );
+    drop(connections);
+    push_peer_list(state, socket);
+}
 
-    if reassign_ip(connections, mac_address, source_address, socket) {
+fn handshake(
+    client_public_key: [u8; 32],
+    source_address: SocketAddr,
+    state: &State,
+    socket: &UdpSocket,
+) {
+    let mut tunnels = state.tunnels.lock().unwrap();
+    let tunnel_ips = tunnels.values().map(|tunnel| tunnel.socket_address.ip());
+    if too_many_tunnels(tunnel_ips, source_address.ip()) {
+        state
+            .rate_limited_handshakes
+            .fetch_add(1, Ordering::Relaxed);
         return;
     }
+    let session_id = loop {
+        let session_id = generate_session_id();
+        if !tunnels.contains_key(&session_id) {
+            break session_id;
+        }
+    };
+    match respond(&state.static_key, client_public_key, session_id) {
+        Ok(HandshakeResponse {
+            public_key,
+            confirmation,
+            session,
+        }) => {
+            let mut keys = KeyRing::default();
+            keys.rotate(session);
+            tunnels.insert(
+                session_id,
+                Tunnel {
+                    keys,
+                    socket_address: source_address,
+                    reassembler: Reassembler::default(),
+                    capabilities: None,
+                    compression_stats: CompressionStats::default(),
+                    next_sequence: 0,
+                    received_sequences: SequenceTracker::default(),
+                    rate_limits: RateLimits::new(state.max_data_rate),
+                    last_seen: Instant::now(),
+                },
+            );
+            send_packet_to(
+                socket,
+                &Packet::HandshakeResponse {
+                    public_key,
+                    static_public_key: state.static_key.public_key(),
+                    confirmation,
+                    session_id,
+                },
+                &source_address,
+            );
+        }
+        Err(_) => {
+            log!("Invalid handshake public key from {source_address}");
+        }
+    }
+}
 
-    if let Some(ip) = get_ip(ip_pool) {
-        log!("Assign IP {ip} to {source_address}");
-        send_to(
-            socket,
-            &Message::RegisterSuccess {
-                ip,
-                subnet_mask: SUBNET_MASK,
-            },
-            &source_address,
-        );
-        connections.lock().unwrap().insert(
-            mac_address,
-            Connection {
-                ip,
-                mac_address,
-                socket_address: source_address,
-                last_seen: Instant::now(),
-            },
-        );
-        ip_pool.lock().unwrap().remove(&ip);
-    } else {
-        send_to(
+/// New keys for an existing session, asked for with the current keys
+fn rekey(client_public_key: [u8; 32], session_id: SessionId, state: &State, socket: &UdpSocket) {
+    let response = match respond(&state.static_key, client_public_key, session_id) {
+        Ok(HandshakeResponse {
+            public_key,
+            confirmation,
+            session,
+        }) => {
+            // Keep the current keys until the client uses the new ones
+            if let Some(tunnel) = state.tunnels.lock().unwrap().get_mut(&session_id) {
+                tunnel.keys.set_next(session);
+            }
+            Message::RekeyResponse {
+                public_key,
+                confirmation,
+            }
+        }
+        Err(_) => Message::RegisterFail {
+            reason: "Invalid rekey public key".to_owned(),
+        },
+    };
+    send_to_client(socket, state, &response, session_id);
+}
+
+fn handle_packet(socket: &UdpSocket, state: &State, buffer: &mut [u8]) {
+    let ReceivePacket {
+        datagram,
+        source_address,
+    } = receive_until_success(socket, buffer, &state.decode_errors);
+    let packet = match datagram {
+        Datagram::Transport {
+            session_id,
+            counter,
+            ciphertext,
+        } => {
+            handle_transport(
+                session_id,
+                counter,
+                ciphertext,
+                source_address,
+                state,
+                socket,
+            );
+            return;
+        }
+        Datagram::Packet(packet) => packet,
+        // Tell it before it finds out by not being able to decode anything we send
+        Datagram::OtherVersionHandshake { .. } => {
+            state
+                .other_version_handshakes
+                .fetch_add(1, Ordering::Relaxed);
+            send_packet_to(
+                socket,
+                &Packet::VersionMismatch {
+                    version: PROTOCOL_VERSION,
+                },
+                &source_address,
+            );
+            return;
+        }
+    };
+    match packet {
+        Packet::HandshakeInit { public_key, cookie } => {
+            // Make sure the client owns its source address before doing anything for it,
+            // the cookie reply is smaller than the request so it can't be used for amplification
+            match cookie {
+                Some(cookie) if state.cookie_generator.verify(&source_address, &cookie) => {
+                    if state
+                        .handshake_limits
+                        .lock()
+                        .unwrap()
+                        .try_take(source_address.ip())
+                    {
+                        handshake(public_key, source_address, state, socket);
+                    } else {
+                        state
+                            .rate_limited_handshakes
+                            .fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                _ => {
+                    send_packet_to(
+                        socket,
+                        &Packet::Cookie {
+                            cookie: state.cookie_generator.generate(&source_address),
+                        },
+                        &source_address,
+                    );
+                }
+            }
+        }
+        Packet::AddressRequest { .. } => {
+            send_packet_to(
+                socket,
+                &Packet::AddressResponse {
+                    mapped_address: canonical_address(source_address),
+                    probe_port: state.probe_port,
+                },
+                &source_address,
+            );
+        }
+        // Ignore invalid packets
+        _ => {
+            state.unexpected_packets.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Packets to the probe port, clients only ask for their address on it
+fn handle_probe_packet(socket: &UdpSocket, state: &State, buffer: &mut [u8]) {
+    let ReceivePacket {
+        datagram,
+        source_address,
+    } = receive_until_success(socket, buffer, &state.decode_errors);
+    if let Datagram::Packet(Packet::AddressRequest { .. }) = datagram {
+        send_packet_to(
             socket,
-            &Message::RegisterFail {
-                reason: "No avalible ip left".to_owned(),
+            &Packet::AddressResponse {
+                mapped_address: canonical_address(source_address),
+                probe_port: None,
             },
             &source_address,
         );
     }
 }
 
+fn handle_transport(
+    session_id: SessionId,
+    counter: u64,
+    ciphertext: &mut [u8],
+    source_address: SocketAddr,
+    state: &State,
+    socket: &UdpSocket,
+) {
+    let mut moved = false;
+    let opened = match state.tunnels.lock().unwrap().get_mut(&session_id) {
+        Some(tunnel) => match tunnel.keys.open(counter, ciphertext) {
+            Ok(opened) => {
+                tunnel.last_seen = Instant::now();
+                // Before rate limiting, it's about what the network lost
+                if let Some(sequence) = opened.data_sequence() {
+                    tunnel.received_sequences.receive(sequence);
+                }
+                // Only authenticated and fresh packets can move the session
+                if tunnel.socket_address != source_address {
+                    log!(
+                        "Session moved from {} to {source_address}",
+                        tunnel.socket_address
+                    );
+                    tunnel.socket_address = source_address;
+                    moved = true;
+                }
+                if !tunnel.rate_limits.try_take(&opened) {
+                    count_rate_limited(&opened, state);
+                    return;
+                }
+                opened
+            }
+            Err(OpenError::Decrypt(_) | OpenError::NoSession) => {
+                state.decryption_failures.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            Err(OpenError::Replay) => {
+                state.replayed_packets.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            Err(OpenError::Decode(_)) => {
+                state.undecodable_messages.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        },
+        // No handshake for this session
+        None => {
+            state.decryption_failures.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+    // Others on direct paths to it need the new endpoint
+    if moved && registered_sender(session_id, state).is_some() {
+        push_peer_list(state, socket);
+    }
+    if !connection_rate_limits_allow(&opened, session_id, state) {
+        count_rate_limited(&opened, state);
+        return;
+    }
+    match opened {
+        Opened::Data { ethernet_frame, .. } => {
+            // Only registered clients can send data into the network
+            if let Some((sender_mac_address, sender_ip)) = registered_sender(session_id, state) {
+                forward_data(ethernet_frame, sender_mac_address, sender_ip, socket, state);
+            }
+        }
+        Opened::Message(Message::Fragment {
+            frame_id,
+            index,
+            count,
+            payload,
+            ..
+        }) => {
+            let Some((sender_mac_address, sender_ip)) = registered_sender(session_id, state) else {
+                return;
+            };
+            let ethernet_frame = state
+                .tunnels
+                .lock()
+                .unwrap()
+                .get_mut(&session_id)
+                .and_then(|tunnel| tunnel.reassembler.add(frame_id, index, count, payload));
+            if let Some(ethernet_frame) = ethernet_frame {
+                forward_data(
+                    &ethernet_frame,
+                    sender_mac_address,
+                    sender_ip,
+                    socket,
+                    state,
+                );
+            }
+        }
+        Opened::Message(Message::CompressedData {
+            frame_length,
+            compressed_frame,
+            ..
+        }) => {
+            let Some((sender_mac_address, sender_ip)) = registered_sender(session_id, state) else {
+                return;
+            };
+            let Some(ethernet_frame) = decompress(frame_length, &compressed_frame) else {
+                log!("Can't decompress frame from {source_address}");
+                return;
+            };
+            if let Some(tunnel) = state.tunnels.lock().unwrap().get_mut(&session_id) {
+                tunnel
+                    .compression_stats
+                    .add(ethernet_frame.len(), compressed_frame.len());
+            }
+            forward_data(
+                &ethernet_frame,
+                sender_mac_address,
+                sender_ip,
+                socket,
+                state,
+            );
+        }
+        Opened::Message(Message::Batch {
+            ethernet_frames, ..
+        }) => {
+            let Some((sender_mac_address, sender_ip)) = registered_sender(session_id, state) else {
+                return;
+            };
+            // Each frame goes through the same checks and to its own recipients
+            for ethernet_frame in &ethernet_frames {
+                forward_data(ethernet_frame, sender_mac_address, sender_ip, socket, state);
+            }
+        }
+        Opened::Message(message) => {
+            handle_message(message, session_id, source_address, state, socket);
+        }
+    }
+}
+
 fn handle_message(
+    message: Message,
+    session_id: SessionId,
+    source_address: SocketAddr,
+    state: &State,
     socket: &UdpSocket,
-    connections: &Mutex<HashMap<MacAddr6, Connection>>,
-    ip_pool: &Mutex<HashSet<Ipv4Addr>>,
 ) {
-    let ReceiveMessage {
-        message,
-        source_address,
-    } = receive_until_success(socket);
     match message {
+        Message::Hello { capabilities, .. } => {
+            hello(capabilities, session_id, state, socket);
+        }
+        Message::Rekey { public_key } => {
+            rekey(public_key, session_id, state, socket);
+        }
         Message::Register { mac_address } => {
-            register(mac_address, source_address, connections, socket, ip_pool);
+            if said_hello(session_id, state) {
+                challenge(mac_address, session_id, source_address, state, socket);
+            } else {
+                send_to_client(
+                    socket,
+                    state,
+                    &Message::RegisterFail {
+                        reason: "Send Hello with the protocol version before registering"
+                            .to_owned(),
+                    },
+                    session_id,
+                );
+            }
         }
-        Message::Data { ethernet_frame } => {
-            forward_data(ethernet_frame, socket, connections);
-            // dbg!(&ethernet_frame);
+        Message::ChallengeResponse { proof } => {
+            if let Some(registration) =
+                verify_challenge_response(proof, session_id, source_address, state, socket)
+            {
+                register(registration, session_id, source_address, state, socket);
+            }
+        }
+        Message::Disconnect => disconnect(session_id, state, socket),
+        Message::PeerRequest { mac_address } => {
+            introduce(mac_address, session_id, state, socket);
+        }
+        Message::MtuProbe { padding } => {
+            let datagram_length = probe_datagram_length(padding.len()) as u32;
+            send_to_client(
+                socket,
+                state,
+                &Message::MtuProbeAck { datagram_length },
+                session_id,
+            );
         }
         Message::Ping => {
             // log!("Ping from {source_address}");
-            if let Some((_, connection)) = connections
-                .lock()
-                .unwrap()
-                .iter_mut()
-                .find(|(_, connection)| connection.socket_address == source_address)
-            {
+            if let Some(connection) = state.connections.lock().unwrap().get_mut(session_id) {
                 connection.last_seen = Instant::now();
-                send_to(socket, &Message::Pong, &connection.socket_address);
+                send_to_client(socket, state, &Message::Pong, session_id);
             }
         }
-        // Ignore invalid pakcets
-        others => {
-            dbg!(others);
+        // Ignore invalid packets
+        _ => {
+            state.unexpected_messages.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Give two registered clients each other's endpoint and a key so they can try a direct path,
+/// not if there are ACL rules since frames on it don't go through them
+fn introduce(mac_address: MacAddr6, session_id: SessionId, state: &State, socket: &UdpSocket) {
+    if state.acl.is_some() {
+        return;
+    }
+    let (requester, peer) = {
+        let connections = state.connections.lock().unwrap();
+        let (Some(requester), Some(peer)) = (
+            connections.get(session_id),
+            connections.get_by_mac_address(&mac_address),
+        ) else {
+            return;
+        };
+        if peer.session_id == session_id {
+            return;
+        }
+        (
+            (requester.mac_address, requester.session_id),
+            (peer.mac_address, peer.session_id),
+        )
+    };
+    let endpoints = {
+        let tunnels = state.tunnels.lock().unwrap();
+        let endpoint = |session_id| {
+            tunnels
+                .get(&session_id)
+                .filter(|tunnel| tunnel.has_capability(CAPABILITY_DIRECT))
+                .map(|tunnel| canonical_address(tunnel.socket_address))
+        };
+        (endpoint(requester.1), endpoint(peer.1))
+    };
+    let (Some(requester_endpoint), Some(peer_endpoint)) = endpoints else {
+        return;
+    };
+    let peer_session_id = generate_session_id();
+    let key = generate_peer_key();
+    send_to_client(
+        socket,
+        state,
+        &Message::PeerIntroduction {
+            mac_address: peer.0,
+            endpoint: peer_endpoint,
+            session_id: peer_session_id,
+            key,
+            initiator: true,
+        },
+        requester.1,
+    );
+    send_to_client(
+        socket,
+        state,
+        &Message::PeerIntroduction {
+            mac_address: requester.0,
+            endpoint: requester_endpoint,
+            session_id: peer_session_id,
+            key,
+            initiator: false,
+        },
+        peer.1,
+    );
+    log!(
+        "Introduced {} and {} for a direct path",
+        requester.0,
+        peer.0
+    );
+}
+
+/// IPv4 clients show up as IPv4-mapped IPv6 addresses on our dual stack socket,
+/// other clients reach them at the IPv4 address
+fn canonical_address(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(address.ip().to_canonical(), address.port())
+}
+
+/// Tell the client why it's dropped and forget its session, it won't try to reconnect
+fn kick(session_id: SessionId, reason: String, state: &State, socket: &UdpSocket) {
+    send_to_client(socket, state, &Message::Kick { reason }, session_id);
+    state.tunnels.lock().unwrap().remove(&session_id);
+}
+
+/// Let every client know so they stop instead of trying to reconnect, then exit
+fn shutdown(state: &State, socket: &UdpSocket) -> ! {
+    log!("Shutting down");
+    let plaintext = encode_message(&Message::ServerShutdown);
+    for tunnel in state.tunnels.lock().unwrap().values() {
+        if let Some(session) = tunnel.keys.current() {
+            send_plaintext_to(socket, session, &plaintext, &tunnel.socket_address);
+        }
+    }
+    std::process::exit(0);
+}
+
+/// Free the IP and forget the session without waiting for it to time out
+fn disconnect(session_id: SessionId, state: &State, socket: &UdpSocket) {
+    let connection = state.connections.lock().unwrap().remove(session_id);
+    state.pending_challenges.lock().unwrap().remove(&session_id);
+    state.tunnels.lock().unwrap().remove(&session_id);
+    if let Some(connection) = connection {
+        state.ip_pool.lock().unwrap().insert(connection.ip);
+        log!(
+            "Disconnected {} ({}), the client left",
+            connection.ip,
+            connection.mac_address
+        );
+        push_peer_list(state, socket);
+    }
+}
+
+/// Send the registered clients to every client that wants them, endpoints are only given out
+/// for direct paths, so not if there are ACL rules
+fn push_peer_list(state: &State, socket: &UdpSocket) {
+    let connections = state.connections.lock().unwrap();
+    let tunnels = state.tunnels.lock().unwrap();
+    let peers: Vec<PeerInfo> = connections
+        .values()
+        .map(|connection| PeerInfo {
+            mac_address: connection.mac_address,
+            ip: connection.ip,
+            endpoint: tunnels
+                .get(&connection.session_id)
+                .filter(|tunnel| state.acl.is_none() && tunnel.has_capability(CAPABILITY_DIRECT))
+                .map(|tunnel| canonical_address(tunnel.socket_address)),
+        })
+        .collect();
+    let generation = state.peer_list_generation.fetch_add(1, Ordering::Relaxed) + 1;
+    let plaintexts = encode_peer_list(generation, &peers);
+    for connection in connections.values() {
+        let Some(tunnel) = tunnels
+            .get(&connection.session_id)
+            .filter(|tunnel| tunnel.has_capability(CAPABILITY_PEER_LIST))
+        else {
+            continue;
+        };
+        if let Some(session) = tunnel.keys.current() {
+            for plaintext in &plaintexts {
+                send_plaintext_to(socket, session, plaintext, &tunnel.socket_address);
+            }
         }
     }
 }
 
+/// Checks the limits of the connection registered by the session,
+/// unregistered sessions only have the limits of their tunnel
+fn connection_rate_limits_allow(opened: &Opened, session_id: SessionId, state: &State) -> bool {
+    state
+        .connections
+        .lock()
+        .unwrap()
+        .get_mut(session_id)
+        .is_none_or(|connection| connection.rate_limits.try_take(opened))
+}
+
+fn count_rate_limited(opened: &Opened, state: &State) {
+    let counter = match opened {
+        Opened::Data { .. } => &state.rate_limited_data,
+        Opened::Message(_) => &state.rate_limited_control,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// MAC address and IP of the client registered by the session
+fn registered_sender(session_id: SessionId, state: &State) -> Option<(MacAddr6, Ipv4Addr)> {
+    state
+        .connections
+        .lock()
+        .unwrap()
+        .get(session_id)
+        .map(|connection| (connection.mac_address, connection.ip))
+}
+
 fn forward_data(
-    ethernet_frame: Vec<u8>,
+    ethernet_frame: &[u8],
+    sender_mac_address: MacAddr6,
+    sender_ip: Ipv4Addr,
     socket: &UdpSocket,
-    connections: &Mutex<HashMap<MacAddr6, Connection>>,
+    state: &State,
 ) {
-    if let Ok((source_mac_address, destination_mac_address)) = get_mac_addresses(&ethernet_frame) {
-        let message = &Message::Data { ethernet_frame };
+    if let Ok((source_mac_address, destination_mac_address)) = get_mac_addresses(ethernet_frame) {
+        // Clients can only send frames from the MAC address they registered with
+        if source_mac_address != sender_mac_address {
+            state.spoofed_frames.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let frame_info = match &state.acl {
+            Some(_) => match FrameInfo::parse(ethernet_frame) {
+                Some(frame_info) => Some(frame_info),
+                // Too short to even have an ethertype
+                None => {
+                    state.denied_frames.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            },
+            None => None,
+        };
+        // Encoded once, only the encryption differs between recipients
+        let plaintexts = &encode_frame(ethernet_frame);
+        // Only compressed once a recipient that negotiated it comes up
+        let compressed = &OnceCell::new();
         let send = |connection: &Connection| {
-            // log!(
-            //     "Forwarding to {} ({})",
-            //     &connection.socket_address, &connection.ip
-            // );
-            send_to(socket, message, &connection.socket_address);
+            if let (Some(acl), Some(frame_info)) = (&state.acl, &frame_info) {
+                if !acl.allows(frame_info, sender_ip, connection.ip) {
+                    state.denied_frames.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            }
+            // log!("Forwarding to {} ({})", connection.session_id, connection.ip);
+            send_frame_to_client(
+                socket,
+                state,
+                ethernet_frame,
+                plaintexts,
+                compressed,
+                connection.session_id,
+            );
         };
         // Broadcast is a special type of multicast
         if destination_mac_address.is_multicast() {
-            for (_, connection) in connections.lock().unwrap().iter() {
+            for connection in state.connections.lock().unwrap().values() {
                 if connection.mac_address != source_mac_address {
                     send(connection);
                 }
             }
-        } else {
-            if let Some(connection) = connections.lock().unwrap().get(&destination_mac_address) {
-                send(connection);
-            }
+        } else if let Some(connection) = state
+            .connections
+            .lock()
+            .unwrap()
+            .get_by_mac_address(&destination_mac_address)
+        {
+            send(connection);
         }
     }
 }
 
-fn purge_timedout_connections(
-    connections: &Mutex<HashMap<MacAddr6, Connection>>,
-    ip_pool: &Mutex<HashSet<Ipv4Addr>>,
+/// Compressed if the client negotiated it and it gets smaller, `plaintexts` from
+/// [`encode_frame`] otherwise
+fn send_frame_to_client(
+    socket: &UdpSocket,
+    state: &State,
+    ethernet_frame: &[u8],
+    plaintexts: &[Vec<u8>],
+    compressed: &OnceCell<Option<Vec<u8>>>,
+    session_id: SessionId,
 ) {
-    connections.lock().unwrap().retain(|_, connection| {
+    let mut tunnels = state.tunnels.lock().unwrap();
+    let Some(tunnel) = tunnels.get_mut(&session_id) else {
+        return;
+    };
+    let Some(session) = tunnel.keys.current() else {
+        return;
+    };
+    let compresses = tunnel.compresses();
+    let mut send = |plaintext: &[u8]| {
+        send_data_plaintext_to(
+            socket,
+            session,
+            plaintext,
+            tunnel.next_sequence,
+            &tunnel.socket_address,
+        );
+        tunnel.next_sequence += 1;
+    };
+    if compresses {
+        if let Some(plaintext) = compressed.get_or_init(|| encode_compressed(ethernet_frame)) {
+            send(plaintext);
+            tunnel
+                .compression_stats
+                .add_compressed(ethernet_frame.len(), plaintext);
+            return;
+        }
+        tunnel.compression_stats.add_raw(ethernet_frame.len());
+    }
+    for plaintext in plaintexts {
+        send(plaintext);
+    }
+}
+
+fn purge_timedout_connections(state: &State) {
+    state.connections.lock().unwrap().retain(|connection| {
         let should_keep = connection.last_seen.elapsed() < Duration::from_secs(200);
         if !should_keep {
             // Release ip from peer
-            ip_pool.lock().unwrap().insert(connection.ip);
+            state.ip_pool.lock().unwrap().insert(connection.ip);
+            log!("Purged {} ({})", connection.ip, connection.mac_address);
+        }
+        should_keep
+    });
+}
+
+fn purge_timedout_challenges(state: &State) {
+    state
+        .pending_challenges
+        .lock()
+        .unwrap()
+        .retain(|_, pending| pending.created_at.elapsed() < CHALLENGE_TIMEOUT);
+}
+
+fn purge_timedout_tunnels(state: &State) {
+    state
+        .tunnels
+        .lock()
+        .unwrap()
+        .retain(|_, tunnel| tunnel.last_seen.elapsed() < SESSION_TIMEOUT);
+}
+
+fn log_dropped_packets(state: &State) {
+    state.decode_errors.log_and_reset();
+    let decryption_failures = state.decryption_failures.swap(0, Ordering::Relaxed);
+    if decryption_failures > 0 {
+        log!("Dropped {decryption_failures} packets that failed decryption");
+    }
+    let other_version_handshakes = state.other_version_handshakes.swap(0, Ordering::Relaxed);
+    if other_version_handshakes > 0 {
+        log!(
+            "Turned down {other_version_handshakes} handshakes from clients of other protocol versions, we use {PROTOCOL_VERSION}"
+        );
+    }
+    let replayed_packets = state.replayed_packets.swap(0, Ordering::Relaxed);
+    if replayed_packets > 0 {
+        log!("Dropped {replayed_packets} replayed or too old packets");
+    }
+    let undecodable_messages = state.undecodable_messages.swap(0, Ordering::Relaxed);
+    if undecodable_messages > 0 {
+        log!("Dropped {undecodable_messages} messages that can't be decoded or are too long");
+    }
+    let unexpected_packets = state.unexpected_packets.swap(0, Ordering::Relaxed);
+    let unexpected_messages = state.unexpected_messages.swap(0, Ordering::Relaxed);
+    if unexpected_packets + unexpected_messages > 0 {
+        log!(
+            "Ignored {unexpected_packets} packets and {unexpected_messages} messages only servers send"
+        );
+    }
+    let spoofed_frames = state.spoofed_frames.swap(0, Ordering::Relaxed);
+    if spoofed_frames > 0 {
+        log!("Dropped {spoofed_frames} frames with a source MAC address not owned by the sender");
+    }
+    let denied_frames = state.denied_frames.swap(0, Ordering::Relaxed);
+    if denied_frames > 0 {
+        log!("Dropped {denied_frames} frames denied by the ACL");
+    }
+    let rate_limited_control = state.rate_limited_control.swap(0, Ordering::Relaxed);
+    if rate_limited_control > 0 {
+        log!("Dropped {rate_limited_control} control messages over the rate limit");
+    }
+    let rate_limited_data = state.rate_limited_data.swap(0, Ordering::Relaxed);
+    if rate_limited_data > 0 {
+        log!("Dropped {rate_limited_data} data messages over the rate limit");
+    }
+    let rate_limited_handshakes = state.rate_limited_handshakes.swap(0, Ordering::Relaxed);
+    if rate_limited_handshakes > 0 {
+        log!("Dropped {rate_limited_handshakes} handshakes over the rate limit or the sessions per IP");
+    }
+}
+
+/// Data lost on the way from each client since the last time and how much compression saved
+fn log_tunnel_stats(state: &State) {
+    for tunnel in state.tunnels.lock().unwrap().values_mut() {
+        let sequence_stats = tunnel.received_sequences.take_stats();
+        if sequence_stats.received > 0 {
+            log!("Data from {} {sequence_stats}", tunnel.socket_address);
+        }
+        if tunnel.compresses() && tunnel.compression_stats.original_bytes > 0 {
             log!(
-                "Purged {} from {}",
+                "Compression with {} {}",
+                tunnel.socket_address,
+                tunnel.compression_stats
+            );
+        }
+    }
+}
+
+/// Reload the identities file if it changed, disconnect clients whose identity got revoked
+/// and move the others to the IP it gives them
+fn reload_identities(state: &State, socket: &UdpSocket) {
+    let Some(identities) = &state.identities else {
+        return;
+    };
+    let mut identities = identities.lock().unwrap();
+    match identities.reload_if_changed() {
+        Ok(true) => {
+            log!("Reloaded {} identities", identities.len());
+        }
+        Ok(false) => return,
+        Err(error) => {
+            log!("Can't reload identities, keep using the old ones: {error}");
+            return;
+        }
+    }
+    state.connections.lock().unwrap().retain(|connection| {
+        let should_keep = connection
+            .identity
+            .is_none_or(|identity| identities.contains(&identity));
+        if !should_keep {
+            state.ip_pool.lock().unwrap().insert(connection.ip);
+            kick(
+                connection.session_id,
+                "Your identity got revoked".to_owned(),
+                state,
+                socket,
+            );
+            log!(
+                "Disconnected {} ({}), its identity got revoked",
                 connection.ip,
-                connection.socket_address
+                connection.mac_address
             );
         }
         should_keep
     });
+    for connection in state.connections.lock().unwrap().values_mut() {
+        let Some(fixed_ip) = connection
+            .identity
+            .and_then(|identity| identities.ip(&identity))
+        else {
+            continue;
+        };
+        if fixed_ip == connection.ip {
+            continue;
+        }
+        if !take_ip(&state.ip_pool, fixed_ip) {
+            log!(
+                "Can't move {} to {fixed_ip}, it's taken or outside the subnet",
+                connection.mac_address
+            );
+            continue;
+        }
+        state.ip_pool.lock().unwrap().insert(connection.ip);
+        log!(
+            "Moved {} from {} to {fixed_ip}",
+            connection.mac_address,
+            connection.ip
+        );
+        connection.ip = fixed_ip;
+        send_to_client(
+            socket,
+            state,
+            &Message::ConfigUpdate {
+                ip: fixed_ip,
+                subnet_mask: SUBNET_MASK,
+            },
+            connection.session_id,
+        );
+    }
+    push_peer_list(state, socket);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC_ADDRESS: MacAddr6 = MacAddr6::new(2, 0, 0, 0, 0, 1);
+    const OWNER_SESSION_ID: SessionId = 1;
+    const NEW_SESSION_ID: SessionId = 2;
+
+    fn owner(identity: Option<[u8; 32]>, last_seen: Instant) -> Connection {
+        Connection {
+            ip: Ipv4Addr::new(10, 123, 123, 2),
+            mac_address: MAC_ADDRESS,
+            session_id: OWNER_SESSION_ID,
+            identity,
+            rate_limits: RateLimits::new(1500),
+            last_seen,
+        }
+    }
+
+    fn registration(identity: Option<[u8; 32]>) -> Registration {
+        Registration {
+            mac_address: MAC_ADDRESS,
+            identity,
+        }
+    }
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([203, 0, 113, 1], port))
+    }
+
+    #[test]
+    fn same_identity_reconnecting_from_elsewhere_is_not_kicked() {
+        // Like after its NAT gave it a new port, with a new handshake
+        let owner = owner(Some([1; 32]), Instant::now());
+        let takeover = takeover(
+            &owner,
+            Some(address(4000)),
+            &registration(Some([1; 32])),
+            NEW_SESSION_ID,
+            address(4001),
+        );
+        assert_eq!(takeover, Takeover::Reconnect);
+    }
+
+    #[test]
+    fn owner_reconnecting_from_the_same_endpoint_is_not_kicked() {
+        let owner = owner(None, Instant::now());
+        let new_session = takeover(
+            &owner,
+            Some(address(4000)),
+            &registration(None),
+            NEW_SESSION_ID,
+            address(4000),
+        );
+        assert_eq!(new_session, Takeover::Reconnect);
+        let same_session = takeover(
+            &owner,
+            Some(address(4001)),
+            &registration(None),
+            OWNER_SESSION_ID,
+            address(4001),
+        );
+        assert_eq!(same_session, Takeover::Reconnect);
+    }
+
+    #[test]
+    fn others_are_refused_until_the_owner_is_gone() {
+        let others = [registration(None), registration(Some([2; 32]))];
+        let owner_identities = [None, Some([1; 32])];
+        for (registration, identity) in others.iter().zip(owner_identities) {
+            let present_owner = owner(identity, Instant::now());
+            let takeover_from = |owner| {
+                takeover(
+                    owner,
+                    Some(address(4000)),
+                    registration,
+                    NEW_SESSION_ID,
+                    address(4001),
+                )
+            };
+            assert_eq!(takeover_from(&present_owner), Takeover::Refuse);
+            let gone_owner = owner(identity, Instant::now() - OWNER_GONE_TIMEOUT);
+            assert_eq!(takeover_from(&gone_owner), Takeover::Kick);
+        }
+    }
 }
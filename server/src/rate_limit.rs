@@ -0,0 +1,209 @@
+use shared::{Message, Opened};
+use std::{collections::HashMap, net::IpAddr, time::Instant};
+
+/// Control messages per second a client can send, bursts up to `CONTROL_BURST`
+const CONTROL_RATE: f64 = 2.0;
+const CONTROL_BURST: f64 = 10.0;
+/// Handshakes per second one IP can start, bursts up to `HANDSHAKE_BURST`
+const HANDSHAKE_RATE: f64 = 0.2;
+const HANDSHAKE_BURST: f64 = 5.0;
+/// Sessions one IP can have at once, clients behind the same NAT share it
+const MAX_TUNNELS_PER_IP: usize = 64;
+
+struct TokenBucket {
+    capacity: f64,
+    /// Tokens added per second
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            capacity,
+            rate,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    fn try_take(&mut self, amount: f64) -> bool {
+        self.refill();
+        if self.tokens < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+}
+
+/// Limits how fast one session or one connection can send
+pub struct RateLimits {
+    /// `Hello`, `Register`, `ChallengeResponse`, `Ping`, `Rekey` and `PeerRequest` messages
+    control: TokenBucket,
+    /// Bytes of ethernet frames in `Data`, `Fragment`, `CompressedData` and `Batch` messages,
+    /// and of padding in `MtuProbe` messages
+    data: TokenBucket,
+}
+
+impl RateLimits {
+    /// `data_rate` is in bytes per second, bursts up to a second of it
+    pub fn new(data_rate: u64) -> Self {
+        Self {
+            control: TokenBucket::new(CONTROL_RATE, CONTROL_BURST),
+            data: TokenBucket::new(data_rate as f64, data_rate as f64),
+        }
+    }
+
+    /// Take the tokens `opened` needs, returns false if it should be dropped
+    pub fn try_take(&mut self, opened: &Opened) -> bool {
+        match opened {
+            Opened::Data { ethernet_frame, .. } => self.data.try_take(ethernet_frame.len() as f64),
+            Opened::Message(
+                Message::Fragment { payload, .. } | Message::MtuProbe { padding: payload },
+            ) => self.data.try_take(payload.len() as f64),
+            // What it decompresses to, so compressing doesn't get around the limit
+            Opened::Message(Message::CompressedData { frame_length, .. }) => {
+                self.data.try_take(*frame_length as f64)
+            }
+            Opened::Message(Message::Batch {
+                ethernet_frames, ..
+            }) => {
+                let length: usize = ethernet_frames.iter().map(Vec::len).sum();
+                self.data.try_take(length as f64)
+            }
+            Opened::Message(
+                Message::Hello { .. }
+                | Message::Register { .. }
+                | Message::ChallengeResponse { .. }
+                | Message::Ping
+                | Message::Rekey { .. }
+                | Message::PeerRequest { .. },
+            ) => self.control.try_take(1.0),
+            Opened::Message(_) => true,
+        }
+    }
+}
+
+/// Limits how fast each IP can start new sessions, checked before the handshake since each one
+/// costs two X25519 operations and gets a session with full rate limits
+#[derive(Default)]
+pub struct HandshakeLimits {
+    buckets: HashMap<IpAddr, TokenBucket>,
+}
+
+impl HandshakeLimits {
+    /// Returns false if the handshake should be dropped
+    pub fn try_take(&mut self, ip: IpAddr) -> bool {
+        self.buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(HANDSHAKE_RATE, HANDSHAKE_BURST))
+            .try_take(1.0)
+    }
+
+    /// Forget IPs whose bucket filled up again, they'd start from a full one anyway
+    pub fn purge_full(&mut self) {
+        self.buckets.retain(|_, bucket| {
+            bucket.refill();
+            bucket.tokens < bucket.capacity
+        });
+    }
+}
+
+/// Whether `ip` already has as many sessions as it can have, `tunnel_ips` are the IPs of all of them
+pub fn too_many_tunnels(tunnel_ips: impl Iterator<Item = IpAddr>, ip: IpAddr) -> bool {
+    tunnel_ips.filter(|tunnel_ip| *tunnel_ip == ip).count() >= MAX_TUNNELS_PER_IP
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn data(ethernet_frame: &[u8]) -> Opened<'_> {
+        Opened::Data {
+            sequence: 0,
+            ethernet_frame,
+        }
+    }
+
+    /// As if `elapsed` passed since the bucket was last refilled
+    fn wait(bucket: &mut TokenBucket, elapsed: Duration) {
+        bucket.last_refill -= elapsed;
+    }
+
+    #[test]
+    fn buckets_refill_at_their_rate_up_to_their_capacity() {
+        let mut bucket = TokenBucket::new(2.0, 10.0);
+        assert!(bucket.try_take(10.0));
+        assert!(!bucket.try_take(1.0));
+        wait(&mut bucket, Duration::from_secs(1));
+        assert!(bucket.try_take(2.0));
+        assert!(!bucket.try_take(1.0));
+        wait(&mut bucket, Duration::from_secs(60));
+        assert!(!bucket.try_take(10.1));
+        assert!(bucket.try_take(10.0));
+    }
+
+    #[test]
+    fn control_and_data_have_separate_limits() {
+        let mut rate_limits = RateLimits::new(1500);
+        for _ in 0..CONTROL_BURST as usize {
+            assert!(rate_limits.try_take(&Opened::Message(Message::Ping)));
+        }
+        assert!(!rate_limits.try_take(&Opened::Message(Message::Ping)));
+        assert!(rate_limits.try_take(&data(&[0; 1500])));
+        assert!(!rate_limits.try_take(&data(&[0; 1])));
+        // Messages that are neither aren't limited
+        assert!(rate_limits.try_take(&Opened::Message(Message::Pong)));
+    }
+
+    #[test]
+    fn new_sessions_do_not_reset_the_connection_limits() {
+        // The server checks the limits of the session and of the connection registered by it,
+        // a reconnecting client gets a new session but keeps its connection's limits
+        let mut connection_limits = RateLimits::new(1500);
+        let mut session_limits = RateLimits::new(1500);
+        assert!(session_limits.try_take(&data(&[0; 1500])));
+        assert!(connection_limits.try_take(&data(&[0; 1500])));
+        let mut new_session_limits = RateLimits::new(1500);
+        assert!(new_session_limits.try_take(&data(&[0; 1500])));
+        assert!(!connection_limits.try_take(&data(&[0; 1500])));
+    }
+
+    #[test]
+    fn handshakes_are_limited_per_ip() {
+        let ip = IpAddr::from([203, 0, 113, 1]);
+        let mut handshake_limits = HandshakeLimits::default();
+        for _ in 0..HANDSHAKE_BURST as usize {
+            assert!(handshake_limits.try_take(ip));
+        }
+        assert!(!handshake_limits.try_take(ip));
+        assert!(handshake_limits.try_take(IpAddr::from([203, 0, 113, 2])));
+        handshake_limits.purge_full();
+        assert_eq!(handshake_limits.buckets.len(), 2);
+        for bucket in handshake_limits.buckets.values_mut() {
+            wait(bucket, Duration::from_secs(60));
+        }
+        handshake_limits.purge_full();
+        assert!(handshake_limits.buckets.is_empty());
+    }
+
+    #[test]
+    fn tunnels_are_capped_per_ip() {
+        let ip = IpAddr::from([203, 0, 113, 1]);
+        let other_ip = IpAddr::from([203, 0, 113, 2]);
+        let tunnel_ips = || std::iter::repeat_n(ip, MAX_TUNNELS_PER_IP - 1).chain([other_ip]);
+        assert!(!too_many_tunnels(tunnel_ips(), ip));
+        assert!(too_many_tunnels(tunnel_ips().chain([ip]), ip));
+        assert!(!too_many_tunnels(tunnel_ips().chain([ip]), other_ip));
+    }
+}
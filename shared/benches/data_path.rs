@@ -0,0 +1,128 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use shared::{
+    crypto::{generate_session_id, respond, Handshake, HandshakeResponse, Session, StaticKey},
+    data::{encode_data, parse_transport, seal_data_datagram, TRANSPORT_HEADER_LENGTH},
+    Message, Opened, Packet, MAGIC, PROTOCOL_VERSION,
+};
+
+const FRAME_LENGTH: usize = 1400;
+const HEADER_LENGTH: usize = MAGIC.len() + 2;
+
+/// Sessions of the server (sending) and the client (receiving) side of a tunnel
+fn session_pair() -> (Session, Session) {
+    let static_key = StaticKey::generate();
+    let handshake = Handshake::new();
+    let session_id = generate_session_id();
+    let HandshakeResponse {
+        public_key,
+        confirmation,
+        session,
+    } = respond(&static_key, handshake.public_key(), session_id).unwrap();
+    let client_session = handshake
+        .finish(
+            public_key,
+            static_key.public_key(),
+            &confirmation,
+            session_id,
+        )
+        .unwrap();
+    (session, client_session)
+}
+
+/// How data frames were sent before the fixed layout, a `Message` and a `Packet` built and
+/// serialized with bincode for every recipient
+fn seal_with_bincode(session: &Session, ethernet_frame: &[u8]) -> Vec<u8> {
+    let message = Message::Data {
+        sequence: 0,
+        ethernet_frame: ethernet_frame.to_vec(),
+    };
+    let (counter, ciphertext) = session.seal(&bincode::serialize(&message).unwrap());
+    let mut datagram = MAGIC.to_vec();
+    datagram.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    bincode::serialize_into(
+        &mut datagram,
+        &Packet::Transport {
+            session_id: session.id(),
+            counter,
+            ciphertext,
+        },
+    )
+    .unwrap();
+    datagram
+}
+
+fn fan_out(criterion: &mut Criterion) {
+    let ethernet_frame = vec![0x42; FRAME_LENGTH];
+    let mut group = criterion.benchmark_group("fan_out");
+    for recipients in [1, 8, 32] {
+        let sessions: Vec<_> = (0..recipients).map(|_| session_pair().0).collect();
+        group.bench_with_input(
+            BenchmarkId::new("bincode", recipients),
+            &sessions,
+            |bencher, sessions| {
+                bencher.iter(|| {
+                    for session in sessions {
+                        black_box(seal_with_bincode(session, &ethernet_frame));
+                    }
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("fixed_layout", recipients),
+            &sessions,
+            |bencher, sessions| {
+                bencher.iter(|| {
+                    let plaintext = encode_data(&ethernet_frame);
+                    for session in sessions {
+                        black_box(seal_data_datagram(session, &plaintext, 0));
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn receive(criterion: &mut Criterion) {
+    let (sender, receiver) = session_pair();
+    let datagram = seal_data_datagram(&sender, &encode_data(&[0x42; FRAME_LENGTH]), 0);
+    let mut group = criterion.benchmark_group("receive");
+    // Replay checks are left out, the same datagram is opened again on every iteration
+    group.bench_function("bincode", |bencher| {
+        bencher.iter(|| {
+            // Copied out of the receive buffer like before
+            let buffer = datagram.clone();
+            let Packet::Transport {
+                counter,
+                ciphertext,
+                ..
+            } = bincode::deserialize(&buffer[HEADER_LENGTH..]).unwrap()
+            else {
+                unreachable!()
+            };
+            let plaintext = receiver.open(counter, &ciphertext).unwrap();
+            let message: Message = bincode::deserialize(&plaintext).unwrap();
+            black_box(message);
+        })
+    });
+    group.bench_function("fixed_layout", |bencher| {
+        bencher.iter(|| {
+            // Stands for the receive buffer, decrypted in place
+            let mut buffer = datagram.clone();
+            let packet = &mut buffer[HEADER_LENGTH..];
+            let (_, counter) = parse_transport(packet).unwrap();
+            let ciphertext = &mut packet[TRANSPORT_HEADER_LENGTH..];
+            let plaintext_length = receiver.open_in_place(counter, ciphertext).unwrap();
+            let Ok(Opened::Data { ethernet_frame, .. }) =
+                Opened::decode(&ciphertext[..plaintext_length])
+            else {
+                unreachable!()
+            };
+            black_box(ethernet_frame);
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, fan_out, receive);
+criterion_main!(benches);
@@ -0,0 +1,164 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use hmac::{Hmac, Mac};
+use macaddr::MacAddr6;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const REGISTER_PROOF_CONTEXT: &[u8] = b"simple-p2p-vpn register";
+const IDENTITY_PROOF_CONTEXT: &[u8] = b"simple-p2p-vpn identity register";
+
+/// How a client proves it's allowed to join the network
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Proof {
+    /// Made by [`register_proof`] with the network key everyone shares
+    NetworkKey([u8; 32]),
+    /// Made by [`IdentityKey::register_proof`] with a key listed in the server's identities file
+    Identity {
+        public_key: [u8; 32],
+        signature: Signature,
+    },
+}
+
+pub fn generate_nonce() -> [u8; 32] {
+    let mut nonce = [0; 32];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+fn register_mac(network_key: &str, nonce: &[u8; 32], mac_address: MacAddr6) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(network_key.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(REGISTER_PROOF_CONTEXT);
+    mac.update(nonce);
+    mac.update(mac_address.as_bytes());
+    mac
+}
+
+/// Proof that we know the network key, bound to the server's nonce and our MAC address
+pub fn register_proof(network_key: &str, nonce: &[u8; 32], mac_address: MacAddr6) -> [u8; 32] {
+    register_mac(network_key, nonce, mac_address)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+/// Constant time check of a proof made by [`register_proof`]
+pub fn verify_register_proof(
+    network_key: &str,
+    nonce: &[u8; 32],
+    mac_address: MacAddr6,
+    proof: &[u8; 32],
+) -> bool {
+    register_mac(network_key, nonce, mac_address)
+        .verify_slice(proof)
+        .is_ok()
+}
+
+fn identity_message(nonce: &[u8; 32], mac_address: MacAddr6) -> Vec<u8> {
+    [IDENTITY_PROOF_CONTEXT, nonce, mac_address.as_bytes()].concat()
+}
+
+/// Private key of a single client, the server only needs to know its public key
+pub struct IdentityKey(SigningKey);
+
+impl IdentityKey {
+    pub fn generate() -> Self {
+        Self(SigningKey::generate(&mut OsRng))
+    }
+
+    pub fn from_hex(hex_secret: &str) -> Option<Self> {
+        let secret: [u8; 32] = hex::decode(hex_secret.trim()).ok()?.try_into().ok()?;
+        Some(Self(SigningKey::from_bytes(&secret)))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0.to_bytes())
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.0.verifying_key().to_bytes()
+    }
+
+    /// Signature over the server's nonce and our MAC address
+    pub fn register_proof(&self, nonce: &[u8; 32], mac_address: MacAddr6) -> Proof {
+        Proof::Identity {
+            public_key: self.public_key(),
+            signature: self.0.sign(&identity_message(nonce, mac_address)),
+        }
+    }
+}
+
+/// Check a signature made by [`IdentityKey::register_proof`]
+pub fn verify_identity_proof(
+    public_key: &[u8; 32],
+    signature: &Signature,
+    nonce: &[u8; 32],
+    mac_address: MacAddr6,
+) -> bool {
+    VerifyingKey::from_bytes(public_key).is_ok_and(|verifying_key| {
+        verifying_key
+            .verify_strict(&identity_message(nonce, mac_address), signature)
+            .is_ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC_ADDRESS: MacAddr6 = MacAddr6::new(2, 0, 0, 0, 0, 1);
+    const OTHER_MAC_ADDRESS: MacAddr6 = MacAddr6::new(2, 0, 0, 0, 0, 2);
+
+    #[test]
+    fn network_key_proofs_need_the_same_key_nonce_and_mac_address() {
+        let nonce = generate_nonce();
+        let other_nonce = generate_nonce();
+        let proof = register_proof("my-secret", &nonce, MAC_ADDRESS);
+        let verify = |network_key, nonce, mac_address| {
+            verify_register_proof(network_key, nonce, mac_address, &proof)
+        };
+        assert!(verify("my-secret", &nonce, MAC_ADDRESS));
+        assert!(!verify("other-secret", &nonce, MAC_ADDRESS));
+        // A proof made for an earlier challenge can't be replayed
+        assert!(!verify("my-secret", &other_nonce, MAC_ADDRESS));
+        assert!(!verify("my-secret", &nonce, OTHER_MAC_ADDRESS));
+    }
+
+    #[test]
+    fn identity_proofs_need_a_valid_signature_over_the_challenge() {
+        let identity_key = IdentityKey::generate();
+        let nonce = generate_nonce();
+        let other_nonce = generate_nonce();
+        let Proof::Identity {
+            public_key,
+            signature,
+        } = identity_key.register_proof(&nonce, MAC_ADDRESS)
+        else {
+            panic!("not an identity proof");
+        };
+        let verify = |public_key, signature, nonce, mac_address| {
+            verify_identity_proof(public_key, signature, nonce, mac_address)
+        };
+        assert!(verify(&public_key, &signature, &nonce, MAC_ADDRESS));
+        assert!(!verify(&public_key, &signature, &other_nonce, MAC_ADDRESS));
+        assert!(!verify(&public_key, &signature, &nonce, OTHER_MAC_ADDRESS));
+        let other_public_key = IdentityKey::generate().public_key();
+        assert!(!verify(&other_public_key, &signature, &nonce, MAC_ADDRESS));
+        let mut bad_signature = signature.to_bytes();
+        bad_signature[0] ^= 1;
+        let bad_signature = Signature::from_bytes(&bad_signature);
+        assert!(!verify(&public_key, &bad_signature, &nonce, MAC_ADDRESS));
+    }
+
+    #[test]
+    fn identity_keys_round_trip_through_hex() {
+        let identity_key = IdentityKey::generate();
+        let loaded = IdentityKey::from_hex(&format!("{}\n", identity_key.to_hex())).unwrap();
+        assert_eq!(loaded.public_key(), identity_key.public_key());
+        assert!(IdentityKey::from_hex("not hex").is_none());
+        assert!(IdentityKey::from_hex(&identity_key.to_hex()[2..]).is_none());
+    }
+}
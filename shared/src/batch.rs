@@ -0,0 +1,150 @@
+use crate::{
+    data::{encode_data, SEQUENCE_LENGTH},
+    encode_message,
+    fragment::MAX_PLAINTEXT_LENGTH,
+    Message,
+};
+use std::{
+    mem,
+    time::{Duration, Instant},
+};
+
+/// Variant, sequence number and frame count of an encoded `Message::Batch`
+const BATCH_HEADER_LENGTH: usize = 4 + SEQUENCE_LENGTH + 8;
+/// Length before each frame in a `Message::Batch`
+const FRAME_HEADER_LENGTH: usize = 8;
+/// Frames bigger than this can't share a datagram with another one of the same size,
+/// they're sent on their own
+const MAX_BATCHED_FRAME_LENGTH: usize =
+    (MAX_PLAINTEXT_LENGTH - BATCH_HEADER_LENGTH) / 2 - FRAME_HEADER_LENGTH;
+
+/// Collects small frames to send them together in one datagram
+pub struct Batcher {
+    ethernet_frames: Vec<Vec<u8>>,
+    /// Of the `Message::Batch` the waiting frames would be encoded to
+    length: usize,
+    /// When the oldest waiting frame was added
+    started_at: Instant,
+}
+
+impl Default for Batcher {
+    fn default() -> Self {
+        Self {
+            ethernet_frames: Vec::new(),
+            length: BATCH_HEADER_LENGTH,
+            started_at: Instant::now(),
+        }
+    }
+}
+
+impl Batcher {
+    pub fn is_batchable(ethernet_frame: &[u8]) -> bool {
+        ethernet_frame.len() <= MAX_BATCHED_FRAME_LENGTH
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ethernet_frames.is_empty()
+    }
+
+    /// Add a frame [`Batcher::is_batchable`] said yes to, returns the plaintext of the
+    /// waiting frames if they have to be sent first to make room for it
+    pub fn add(&mut self, ethernet_frame: &[u8]) -> Option<Vec<u8>> {
+        let frame_length = FRAME_HEADER_LENGTH + ethernet_frame.len();
+        let full = if self.length + frame_length > MAX_PLAINTEXT_LENGTH {
+            self.take()
+        } else {
+            None
+        };
+        if self.is_empty() {
+            self.started_at = Instant::now();
+        }
+        self.ethernet_frames.push(ethernet_frame.to_vec());
+        self.length += frame_length;
+        full
+    }
+
+    /// When the oldest waiting frame has waited `delay`, `None` if nothing is waiting
+    pub fn deadline(&self, delay: Duration) -> Option<Instant> {
+        (!self.is_empty()).then(|| self.started_at + delay)
+    }
+
+    /// Plaintext to send the waiting frames with, a `Message::Data` if there's only one
+    pub fn take(&mut self) -> Option<Vec<u8>> {
+        self.length = BATCH_HEADER_LENGTH;
+        let mut ethernet_frames = mem::take(&mut self.ethernet_frames);
+        match ethernet_frames.len() {
+            0 => None,
+            1 => Some(encode_data(&ethernet_frames.pop().unwrap())),
+            _ => Some(encode_message(&Message::Batch {
+                sequence: 0,
+                ethernet_frames,
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::peer_session, data::seal_data_datagram, decode_message,
+        fragment::MAX_DATAGRAM_LENGTH, Opened,
+    };
+
+    /// Add frames until the batcher hands back the full batch
+    fn fill(batcher: &mut Batcher, ethernet_frame: &[u8]) -> (usize, Vec<u8>) {
+        let mut added = 0;
+        loop {
+            if let Some(plaintext) = batcher.add(ethernet_frame) {
+                return (added, plaintext);
+            }
+            added += 1;
+        }
+    }
+
+    #[test]
+    fn full_batches_fit_in_one_datagram() {
+        let session = peer_session(&[7; 32], true, 1);
+        for frame_length in [1, 60, MAX_BATCHED_FRAME_LENGTH] {
+            let mut batcher = Batcher::default();
+            let (added, plaintext) = fill(&mut batcher, &vec![0; frame_length]);
+            assert!(added >= 2);
+            let datagram = seal_data_datagram(&session, &plaintext, u64::MAX);
+            assert!(datagram.len() <= MAX_DATAGRAM_LENGTH, "{frame_length}");
+            match decode_message(&plaintext).unwrap() {
+                Message::Batch {
+                    ethernet_frames, ..
+                } => assert_eq!(ethernet_frames.len(), added),
+                _ => panic!("not a batch"),
+            }
+        }
+    }
+
+    #[test]
+    fn two_of_the_biggest_batchable_frames_fit_together() {
+        let mut batcher = Batcher::default();
+        let ethernet_frame = vec![0; MAX_BATCHED_FRAME_LENGTH];
+        assert!(Batcher::is_batchable(&ethernet_frame));
+        assert!(!Batcher::is_batchable(&[0; MAX_BATCHED_FRAME_LENGTH + 1]));
+        assert_eq!(batcher.add(&ethernet_frame), None);
+        assert_eq!(batcher.add(&ethernet_frame), None);
+        let plaintext = batcher.take().unwrap();
+        assert!(plaintext.len() <= MAX_PLAINTEXT_LENGTH);
+        assert!(batcher.is_empty());
+    }
+
+    #[test]
+    fn a_single_frame_is_sent_as_data() {
+        let mut batcher = Batcher::default();
+        assert_eq!(batcher.deadline(Duration::from_millis(1)), None);
+        assert_eq!(batcher.take(), None);
+        batcher.add(&[1; 60]);
+        assert!(batcher.deadline(Duration::from_millis(1)).is_some());
+        let plaintext = batcher.take().unwrap();
+        assert!(matches!(
+            Opened::decode(&plaintext),
+            Ok(Opened::Data { ethernet_frame, .. }) if ethernet_frame == [1; 60]
+        ));
+        assert_eq!(batcher.deadline(Duration::from_millis(1)), None);
+    }
+}
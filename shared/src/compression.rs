@@ -0,0 +1,140 @@
+use crate::{
+    data::SEQUENCE_LENGTH, encode_message, fragment::MAX_PLAINTEXT_LENGTH, Message,
+    MAX_FRAME_LENGTH,
+};
+use std::fmt;
+
+/// Variant, sequence number, frame length and compressed length
+/// of an encoded `Message::CompressedData`
+const COMPRESSED_DATA_HEADER_LENGTH: usize = 4 + SEQUENCE_LENGTH + 4 + 8;
+/// Variant, sequence number and length of an encoded `Message::Data`
+const DATA_HEADER_LENGTH: usize = 4 + SEQUENCE_LENGTH + 8;
+
+/// Plaintext of a `Message::CompressedData`, `None` if compressing doesn't make it smaller
+/// or it wouldn't fit in one datagram, send those raw
+pub fn encode_compressed(ethernet_frame: &[u8]) -> Option<Vec<u8>> {
+    let compressed_frame = lz4_flex::compress(ethernet_frame);
+    let length = COMPRESSED_DATA_HEADER_LENGTH + compressed_frame.len();
+    if length >= DATA_HEADER_LENGTH + ethernet_frame.len() || length > MAX_PLAINTEXT_LENGTH {
+        return None;
+    }
+    Some(encode_message(&Message::CompressedData {
+        sequence: 0,
+        frame_length: ethernet_frame.len() as u32,
+        compressed_frame,
+    }))
+}
+
+/// `None` if it's corrupted or claims to be bigger than a frame can be
+pub fn decompress(frame_length: u32, compressed_frame: &[u8]) -> Option<Vec<u8>> {
+    let frame_length = frame_length as usize;
+    if frame_length > MAX_FRAME_LENGTH {
+        return None;
+    }
+    let ethernet_frame = lz4_flex::decompress(compressed_frame, frame_length).ok()?;
+    (ethernet_frame.len() == frame_length).then_some(ethernet_frame)
+}
+
+/// Bytes of frames before and after compression, frames sent raw count the same for both
+#[derive(Default)]
+pub struct CompressionStats {
+    pub original_bytes: u64,
+    pub compressed_bytes: u64,
+}
+
+impl CompressionStats {
+    pub fn add(&mut self, original_bytes: usize, compressed_bytes: usize) {
+        self.original_bytes += original_bytes as u64;
+        self.compressed_bytes += compressed_bytes as u64;
+    }
+
+    /// A frame sent as a `plaintext` from [`encode_compressed`]
+    pub fn add_compressed(&mut self, frame_length: usize, plaintext: &[u8]) {
+        self.add(
+            frame_length,
+            plaintext.len() - COMPRESSED_DATA_HEADER_LENGTH,
+        );
+    }
+
+    pub fn add_raw(&mut self, frame_length: usize) {
+        self.add(frame_length, frame_length);
+    }
+}
+
+impl fmt::Display for CompressionStats {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let saved_bytes = self.original_bytes.saturating_sub(self.compressed_bytes);
+        let percentage = match self.original_bytes {
+            0 => 0.0,
+            original_bytes => saved_bytes as f64 / original_bytes as f64 * 100.0,
+        };
+        write!(
+            formatter,
+            "saved {saved_bytes} of {} bytes ({percentage:.1}%)",
+            self.original_bytes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode_message;
+    use rand::RngCore;
+
+    /// Frame length and compressed frame of a `Message::CompressedData` plaintext
+    fn compressed_data(plaintext: &[u8]) -> (u32, Vec<u8>) {
+        match decode_message(plaintext).unwrap() {
+            Message::CompressedData {
+                frame_length,
+                compressed_frame,
+                ..
+            } => (frame_length, compressed_frame),
+            _ => panic!("not a compressed data message"),
+        }
+    }
+
+    #[test]
+    fn compressible_frames_round_trip() {
+        let ethernet_frame: Vec<u8> = (0..1500).map(|byte| (byte % 16) as u8).collect();
+        let plaintext = encode_compressed(&ethernet_frame).unwrap();
+        assert!(plaintext.len() < ethernet_frame.len());
+        let (frame_length, compressed_frame) = compressed_data(&plaintext);
+        assert_eq!(frame_length, 1500);
+        assert_eq!(
+            decompress(frame_length, &compressed_frame),
+            Some(ethernet_frame)
+        );
+        let mut stats = CompressionStats::default();
+        stats.add_compressed(1500, &plaintext);
+        stats.add_raw(500);
+        assert_eq!(stats.original_bytes, 2000);
+        assert_eq!(stats.compressed_bytes, compressed_frame.len() as u64 + 500);
+    }
+
+    #[test]
+    fn incompressible_frames_are_sent_raw() {
+        let mut ethernet_frame = vec![0; 1500];
+        rand::thread_rng().fill_bytes(&mut ethernet_frame);
+        assert_eq!(encode_compressed(&ethernet_frame), None);
+        // Too small to get any smaller with the header
+        assert_eq!(encode_compressed(&[0; 8]), None);
+    }
+
+    #[test]
+    fn frame_lengths_that_do_not_add_up_are_rejected() {
+        let ethernet_frame = vec![0; 1500];
+        let (frame_length, compressed_frame) =
+            compressed_data(&encode_compressed(&ethernet_frame).unwrap());
+        assert_eq!(decompress(frame_length - 1, &compressed_frame), None);
+        assert_eq!(decompress(frame_length + 1, &compressed_frame), None);
+        // Turned down before anything is allocated for it
+        assert_eq!(
+            decompress(MAX_FRAME_LENGTH as u32 + 1, &compressed_frame),
+            None
+        );
+        assert_eq!(decompress(u32::MAX, &compressed_frame), None);
+        let corrupted = &compressed_frame[..compressed_frame.len() - 1];
+        assert_eq!(decompress(frame_length, corrupted), None);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::auth::generate_nonce;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A cookie is valid for the time bucket it was made in and the one after it
+const COOKIE_BUCKET_SECONDS: u64 = 60;
+
+/// Stateless proof that a client can receive packets at its source address,
+/// the server can check it without remembering anything about the client
+pub struct CookieGenerator {
+    secret: [u8; 32],
+}
+
+impl CookieGenerator {
+    pub fn new() -> Self {
+        Self {
+            secret: generate_nonce(),
+        }
+    }
+
+    fn cookie_mac(&self, source_address: &SocketAddr, bucket: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).unwrap();
+        mac.update(&bucket.to_le_bytes());
+        mac.update(source_address.to_string().as_bytes());
+        mac
+    }
+
+    fn current_bucket() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / COOKIE_BUCKET_SECONDS
+    }
+
+    pub fn generate(&self, source_address: &SocketAddr) -> [u8; 16] {
+        self.generate_in(source_address, Self::current_bucket())
+    }
+
+    fn generate_in(&self, source_address: &SocketAddr, bucket: u64) -> [u8; 16] {
+        self.cookie_mac(source_address, bucket)
+            .finalize()
+            .into_bytes()[..16]
+            .try_into()
+            .unwrap()
+    }
+
+    pub fn verify(&self, source_address: &SocketAddr, cookie: &[u8; 16]) -> bool {
+        self.verify_in(source_address, cookie, Self::current_bucket())
+    }
+
+    fn verify_in(&self, source_address: &SocketAddr, cookie: &[u8; 16], bucket: u64) -> bool {
+        [bucket, bucket.saturating_sub(1)]
+            .into_iter()
+            .any(|bucket| {
+                self.cookie_mac(source_address, bucket)
+                    .verify_truncated_left(cookie)
+                    .is_ok()
+            })
+    }
+}
+
+impl Default for CookieGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_datagram, encode_packet, Packet};
+
+    const BUCKET: u64 = 1000;
+
+    fn address() -> SocketAddr {
+        "203.0.113.1:4000".parse().unwrap()
+    }
+
+    #[test]
+    fn cookies_are_valid_for_their_bucket_and_the_next_one() {
+        let generator = CookieGenerator::new();
+        let cookie = generator.generate_in(&address(), BUCKET);
+        assert!(generator.verify_in(&address(), &cookie, BUCKET));
+        assert!(generator.verify_in(&address(), &cookie, BUCKET + 1));
+        assert!(!generator.verify_in(&address(), &cookie, BUCKET + 2));
+        assert!(!generator.verify_in(&address(), &cookie, BUCKET - 1));
+        let cookie = generator.generate(&address());
+        assert!(generator.verify(&address(), &cookie));
+    }
+
+    #[test]
+    fn cookies_are_bound_to_the_address_and_secret() {
+        let generator = CookieGenerator::new();
+        let cookie = generator.generate_in(&address(), BUCKET);
+        for other_address in ["203.0.113.1:4001", "203.0.113.2:4000", "[::1]:4000"] {
+            let other_address = other_address.parse().unwrap();
+            assert!(!generator.verify_in(&other_address, &cookie, BUCKET));
+        }
+        let mut tampered = cookie;
+        tampered[15] ^= 1;
+        assert!(!generator.verify_in(&address(), &tampered, BUCKET));
+        // Like after a server restart
+        assert!(!CookieGenerator::new().verify_in(&address(), &cookie, BUCKET));
+    }
+
+    #[test]
+    fn truncated_cookies_are_not_decoded() {
+        let datagram = encode_packet(&Packet::HandshakeInit {
+            public_key: [1; 32],
+            cookie: Some(CookieGenerator::new().generate(&address())),
+        });
+        assert!(decode_datagram(&datagram).is_ok());
+        assert!(decode_datagram(&datagram[..datagram.len() - 1]).is_err());
+    }
+}
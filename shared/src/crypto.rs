@@ -0,0 +1,483 @@
+use crate::{replay::ReplayWindow, SessionId};
+use chacha20poly1305::{
+    aead::{Aead, AeadInPlace},
+    ChaCha20Poly1305, KeyInit, Nonce, Tag,
+};
+use hkdf::Hkdf;
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use x25519_dalek::{EphemeralSecret, PublicKey, ReusableSecret, SharedSecret, StaticSecret};
+
+const HANDSHAKE_CONTEXT: &[u8] = b"simple-p2p-vpn handshake";
+const PEER_CONTEXT: &[u8] = b"simple-p2p-vpn peer";
+const PEER_REKEY_CONTEXT: &[u8] = b"simple-p2p-vpn peer rekey";
+// Counter 0 is used by the handshake key confirmation, transport messages start from 1
+const CONFIRMATION_COUNTER: u64 = 0;
+pub const TAG_LENGTH: usize = 16;
+
+#[derive(Debug)]
+pub struct DecryptError;
+
+/// Client side of the handshake, kept until the server responds
+pub struct Handshake {
+    secret: ReusableSecret,
+    public_key: PublicKey,
+}
+
+impl Handshake {
+    pub fn new() -> Self {
+        let secret = ReusableSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret);
+        Self { secret, public_key }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public_key.to_bytes()
+    }
+
+    /// Derive the session from the server's response, fails if the server didn't derive
+    /// the same keys as us, which also proves it holds the private key of `server_static_key`
+    pub fn finish(
+        self,
+        server_public_key: [u8; 32],
+        server_static_key: [u8; 32],
+        confirmation: &[u8; 16],
+        session_id: SessionId,
+    ) -> Result<Session, DecryptError> {
+        let server_public_key = PublicKey::from(server_public_key);
+        let server_static_key = PublicKey::from(server_static_key);
+        let ephemeral_shared_secret = self.secret.diffie_hellman(&server_public_key);
+        let static_shared_secret = self.secret.diffie_hellman(&server_static_key);
+        if !ephemeral_shared_secret.was_contributory() || !static_shared_secret.was_contributory() {
+            return Err(DecryptError);
+        }
+        let session = Session::derive(
+            &ephemeral_shared_secret,
+            &static_shared_secret,
+            [&self.public_key, &server_public_key, &server_static_key],
+            Role::Client,
+            session_id,
+        );
+        session.open(CONFIRMATION_COUNTER, confirmation)?;
+        Ok(session)
+    }
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Long term key of the server, clients can pin its public key to make sure
+/// they are talking to the right server
+pub struct StaticKey {
+    secret: StaticSecret,
+    public_key: PublicKey,
+}
+
+impl StaticKey {
+    pub fn generate() -> Self {
+        StaticSecret::random_from_rng(OsRng).into()
+    }
+
+    pub fn from_hex(hex_secret: &str) -> Option<Self> {
+        let secret: [u8; 32] = hex::decode(hex_secret.trim()).ok()?.try_into().ok()?;
+        Some(StaticSecret::from(secret).into())
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.secret.as_bytes())
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public_key.to_bytes()
+    }
+}
+
+impl From<StaticSecret> for StaticKey {
+    fn from(secret: StaticSecret) -> Self {
+        let public_key = PublicKey::from(&secret);
+        Self { secret, public_key }
+    }
+}
+
+pub fn parse_public_key(hex_public_key: &str) -> Result<[u8; 32], String> {
+    hex::decode(hex_public_key.trim())
+        .map_err(|error| error.to_string())?
+        .try_into()
+        .map_err(|_| "Public key should be 32 bytes".to_owned())
+}
+
+pub fn generate_session_id() -> SessionId {
+    OsRng.next_u64()
+}
+
+/// Key the server hands to two clients for a direct path between them
+pub fn generate_peer_key() -> [u8; 32] {
+    let mut key = [0; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+/// Session of a direct path between two clients from the key the server gave both of them,
+/// the initiator and the other peer send with different keys
+pub fn peer_session(key: &[u8; 32], initiator: bool, id: SessionId) -> Session {
+    let hkdf = Hkdf::<Sha256>::new(Some(PEER_CONTEXT), key);
+    let mut keys = [0; 64];
+    hkdf.expand(&id.to_le_bytes(), &mut keys).unwrap();
+    let role = if initiator {
+        Role::Client
+    } else {
+        Role::Server
+    };
+    Session::from_keys(&keys, role, id)
+}
+
+/// Key of the next session of a direct path, both peers move on to it by themselves
+/// so neither has to wait for the other, and the previous keys can't be worked out from it
+pub fn next_peer_key(key: &[u8; 32]) -> [u8; 32] {
+    let hkdf = Hkdf::<Sha256>::new(Some(PEER_REKEY_CONTEXT), key);
+    let mut next_key = [0; 32];
+    hkdf.expand(&[], &mut next_key).unwrap();
+    next_key
+}
+
+pub struct HandshakeResponse {
+    pub public_key: [u8; 32],
+    pub confirmation: [u8; 16],
+    pub session: Session,
+}
+
+/// Server side of the handshake, `session_id` is the one of a new session or the one being rekeyed
+pub fn respond(
+    static_key: &StaticKey,
+    client_public_key: [u8; 32],
+    session_id: SessionId,
+) -> Result<HandshakeResponse, DecryptError> {
+    let client_public_key = PublicKey::from(client_public_key);
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let public_key = PublicKey::from(&secret);
+    let ephemeral_shared_secret = secret.diffie_hellman(&client_public_key);
+    let static_shared_secret = static_key.secret.diffie_hellman(&client_public_key);
+    if !ephemeral_shared_secret.was_contributory() || !static_shared_secret.was_contributory() {
+        return Err(DecryptError);
+    }
+    let session = Session::derive(
+        &ephemeral_shared_secret,
+        &static_shared_secret,
+        [&client_public_key, &public_key, &static_key.public_key],
+        Role::Server,
+        session_id,
+    );
+    let confirmation = session
+        .sender
+        .encrypt(&counter_nonce(CONFIRMATION_COUNTER), [].as_slice())
+        .unwrap()
+        .try_into()
+        .unwrap();
+    Ok(HandshakeResponse {
+        public_key: public_key.to_bytes(),
+        confirmation,
+        session,
+    })
+}
+
+enum Role {
+    Client,
+    Server,
+}
+
+fn counter_nonce(counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// Keys derived from a handshake, one for each direction
+pub struct Session {
+    /// Picked by the server, sent in the clear with every transport packet
+    /// so the server finds the session no matter which endpoint it comes from
+    id: SessionId,
+    sender: ChaCha20Poly1305,
+    receiver: ChaCha20Poly1305,
+    send_counter: AtomicU64,
+    replay_window: Mutex<ReplayWindow>,
+    created_at: Instant,
+    /// Sealed and opened bytes, for deciding when to rekey
+    transferred_bytes: AtomicU64,
+}
+
+impl Session {
+    /// `public_keys` are the client ephemeral, server ephemeral and server static keys,
+    /// the ID goes into the keys too so a confirmation only finishes the session it was made for
+    fn derive(
+        ephemeral_shared_secret: &SharedSecret,
+        static_shared_secret: &SharedSecret,
+        public_keys: [&PublicKey; 3],
+        role: Role,
+        id: SessionId,
+    ) -> Self {
+        let mut shared_secrets = [0; 64];
+        shared_secrets[..32].copy_from_slice(ephemeral_shared_secret.as_bytes());
+        shared_secrets[32..].copy_from_slice(static_shared_secret.as_bytes());
+        let hkdf = Hkdf::<Sha256>::new(Some(HANDSHAKE_CONTEXT), &shared_secrets);
+        let [client_ephemeral_key, server_ephemeral_key, server_static_key] = public_keys;
+        let mut keys = [0; 64];
+        hkdf.expand_multi_info(
+            &[
+                client_ephemeral_key.as_bytes(),
+                server_ephemeral_key.as_bytes(),
+                server_static_key.as_bytes(),
+                &id.to_le_bytes(),
+            ],
+            &mut keys,
+        )
+        .unwrap();
+        Self::from_keys(&keys, role, id)
+    }
+
+    /// `keys` are the client to server key followed by the server to client key
+    fn from_keys(keys: &[u8; 64], role: Role, id: SessionId) -> Self {
+        let client_to_server = ChaCha20Poly1305::new_from_slice(&keys[..32]).unwrap();
+        let server_to_client = ChaCha20Poly1305::new_from_slice(&keys[32..]).unwrap();
+        let (sender, receiver) = match role {
+            Role::Client => (client_to_server, server_to_client),
+            Role::Server => (server_to_client, client_to_server),
+        };
+        Self {
+            id,
+            sender,
+            receiver,
+            send_counter: AtomicU64::new(CONFIRMATION_COUNTER + 1),
+            replay_window: Mutex::new(ReplayWindow::default()),
+            created_at: Instant::now(),
+            transferred_bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// Encrypt with the next counter, returns the counter used and the ciphertext
+    pub fn seal(&self, plaintext: &[u8]) -> (u64, Vec<u8>) {
+        let counter = self.send_counter.fetch_add(1, Ordering::Relaxed);
+        let ciphertext = self
+            .sender
+            .encrypt(&counter_nonce(counter), plaintext)
+            .unwrap();
+        self.transferred_bytes
+            .fetch_add(plaintext.len() as u64, Ordering::Relaxed);
+        (counter, ciphertext)
+    }
+
+    /// Encrypt `buffer` in place with the next counter, returns the counter used and the tag
+    /// to append after the ciphertext
+    pub fn seal_in_place(&self, buffer: &mut [u8]) -> (u64, [u8; TAG_LENGTH]) {
+        let counter = self.send_counter.fetch_add(1, Ordering::Relaxed);
+        let tag = self
+            .sender
+            .encrypt_in_place_detached(&counter_nonce(counter), &[], buffer)
+            .unwrap();
+        self.transferred_bytes
+            .fetch_add(buffer.len() as u64, Ordering::Relaxed);
+        (counter, tag.into())
+    }
+
+    pub fn open(&self, counter: u64, ciphertext: &[u8]) -> Result<Vec<u8>, DecryptError> {
+        let plaintext = self
+            .receiver
+            .decrypt(&counter_nonce(counter), ciphertext)
+            .map_err(|_| DecryptError)?;
+        self.transferred_bytes
+            .fetch_add(plaintext.len() as u64, Ordering::Relaxed);
+        Ok(plaintext)
+    }
+
+    /// Decrypt a ciphertext followed by its tag in place, returns the length of the plaintext
+    /// at the start of `ciphertext`, which is left untouched if the decryption fails
+    pub fn open_in_place(
+        &self,
+        counter: u64,
+        ciphertext: &mut [u8],
+    ) -> Result<usize, DecryptError> {
+        let plaintext_length = ciphertext
+            .len()
+            .checked_sub(TAG_LENGTH)
+            .ok_or(DecryptError)?;
+        let (plaintext, tag) = ciphertext.split_at_mut(plaintext_length);
+        self.receiver
+            .decrypt_in_place_detached(
+                &counter_nonce(counter),
+                &[],
+                plaintext,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| DecryptError)?;
+        self.transferred_bytes
+            .fetch_add(plaintext_length as u64, Ordering::Relaxed);
+        Ok(plaintext_length)
+    }
+
+    /// Whether this session is old enough or used enough to be replaced with a new handshake
+    pub fn needs_rekey(&self, max_age: Duration, max_bytes: u64) -> bool {
+        self.created_at.elapsed() >= max_age
+            || self.transferred_bytes.load(Ordering::Relaxed) >= max_bytes
+    }
+
+    /// Record the counter of a packet that passed [`Session::open`],
+    /// returns false if it's a replay or too old
+    pub fn accept_counter(&self, counter: u64) -> bool {
+        self.replay_window.lock().unwrap().check_and_update(counter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION_ID: SessionId = 7;
+
+    /// Client and server sessions from a handshake
+    fn sessions(static_key: &StaticKey) -> (Session, Session) {
+        let handshake = Handshake::new();
+        let response = respond(static_key, handshake.public_key(), SESSION_ID).unwrap();
+        let client_session = handshake
+            .finish(
+                response.public_key,
+                static_key.public_key(),
+                &response.confirmation,
+                SESSION_ID,
+            )
+            .unwrap();
+        (client_session, response.session)
+    }
+
+    #[test]
+    fn handshake_gives_both_sides_the_same_keys() {
+        let (client_session, server_session) = sessions(&StaticKey::generate());
+        assert_eq!(client_session.id(), SESSION_ID);
+        assert_eq!(server_session.id(), SESSION_ID);
+        let (counter, ciphertext) = client_session.seal(b"hello");
+        assert_eq!(server_session.open(counter, &ciphertext).unwrap(), b"hello");
+        let (counter, ciphertext) = server_session.seal(b"hi");
+        assert_eq!(client_session.open(counter, &ciphertext).unwrap(), b"hi");
+        // Each direction has its own key
+        assert!(client_session
+            .open(counter, &client_session.seal(b"hi").1)
+            .is_err());
+    }
+
+    #[test]
+    fn handshake_fails_with_the_wrong_response() {
+        let static_key = StaticKey::generate();
+        let finish = |static_public_key, tamper: fn(&mut HandshakeResponse), session_id| {
+            let handshake = Handshake::new();
+            let mut response = respond(&static_key, handshake.public_key(), SESSION_ID).unwrap();
+            tamper(&mut response);
+            handshake
+                .finish(
+                    response.public_key,
+                    static_public_key,
+                    &response.confirmation,
+                    session_id,
+                )
+                .is_ok()
+        };
+        assert!(finish(static_key.public_key(), |_| {}, SESSION_ID));
+        // Someone else pretending to be the server
+        let other_static_key = StaticKey::generate().public_key();
+        assert!(!finish(other_static_key, |_| {}, SESSION_ID));
+        assert!(!finish(
+            static_key.public_key(),
+            |response| response.confirmation[0] ^= 1,
+            SESSION_ID
+        ));
+        assert!(!finish(
+            static_key.public_key(),
+            |response| response.public_key = StaticKey::generate().public_key(),
+            SESSION_ID
+        ));
+        // The confirmation is bound to the session ID it was made for
+        assert!(!finish(static_key.public_key(), |_| {}, SESSION_ID + 1));
+    }
+
+    #[test]
+    fn handshake_rejects_low_order_public_keys() {
+        let static_key = StaticKey::generate();
+        assert!(respond(&static_key, [0; 32], SESSION_ID).is_err());
+        let handshake = Handshake::new();
+        let response = respond(&static_key, handshake.public_key(), SESSION_ID).unwrap();
+        let finished = handshake.finish(
+            [0; 32],
+            static_key.public_key(),
+            &response.confirmation,
+            SESSION_ID,
+        );
+        assert!(finished.is_err());
+        let handshake = Handshake::new();
+        let finished = handshake.finish(
+            response.public_key,
+            [0; 32],
+            &response.confirmation,
+            SESSION_ID,
+        );
+        assert!(finished.is_err());
+    }
+
+    #[test]
+    fn sealing_and_opening_in_place_interoperate() {
+        let (client_session, server_session) = sessions(&StaticKey::generate());
+        let (counter, mut ciphertext) = client_session.seal(b"hello");
+        let plaintext_length = server_session
+            .open_in_place(counter, &mut ciphertext)
+            .unwrap();
+        assert_eq!(&ciphertext[..plaintext_length], b"hello");
+
+        let mut buffer = b"hello".to_vec();
+        let (counter, tag) = client_session.seal_in_place(&mut buffer);
+        assert_ne!(buffer, b"hello");
+        buffer.extend_from_slice(&tag);
+        assert_eq!(server_session.open(counter, &buffer).unwrap(), b"hello");
+
+        // Tampered ciphertexts are left untouched
+        let (counter, ciphertext) = client_session.seal(b"hello");
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        let untouched = tampered.clone();
+        assert!(server_session
+            .open_in_place(counter, &mut tampered)
+            .is_err());
+        assert_eq!(tampered, untouched);
+
+        let mut too_short = ciphertext[..TAG_LENGTH - 1].to_vec();
+        assert!(server_session
+            .open_in_place(counter, &mut too_short)
+            .is_err());
+        assert!(server_session.open(counter, &too_short).is_err());
+    }
+
+    #[test]
+    fn rekey_is_needed_after_enough_time_or_bytes() {
+        let (client_session, server_session) = sessions(&StaticKey::generate());
+        let hour = Duration::from_secs(3600);
+        assert!(!client_session.needs_rekey(hour, 100));
+        assert!(client_session.needs_rekey(Duration::ZERO, 100));
+        let (counter, ciphertext) = client_session.seal(&[0; 99]);
+        assert!(!client_session.needs_rekey(hour, 100));
+        client_session.seal(&[0; 1]);
+        assert!(client_session.needs_rekey(hour, 100));
+        // Opened bytes count too
+        server_session.open(counter, &ciphertext).unwrap();
+        assert!(!server_session.needs_rekey(hour, 100));
+        assert!(server_session.needs_rekey(hour, 99));
+    }
+}
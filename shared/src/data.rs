@@ -0,0 +1,203 @@
+use crate::{
+    crypto::{Session, TAG_LENGTH},
+    SessionId, HEADER_LENGTH, MAGIC, PROTOCOL_VERSION,
+};
+
+// Fixed layout of the packets on the data path, the same bytes bincode produces for
+// `Packet::Transport` and `Message::Data`, but written once per frame and parsed by borrowing
+// the receive buffer instead of going through serde. bincode writes enum variants as u32
+// and lengths as u64, all little endian, so the variant indexes below must follow the enums
+const TRANSPORT_VARIANT: u32 = 3;
+const DATA_VARIANT: u32 = 7;
+const VARIANT_LENGTH: usize = 4;
+const SESSION_ID_LENGTH: usize = 8;
+const COUNTER_LENGTH: usize = 8;
+/// Every data message starts with its sequence number after the variant
+pub const SEQUENCE_LENGTH: usize = 8;
+const LENGTH_LENGTH: usize = 8;
+/// Variant, session ID, counter and ciphertext length before the ciphertext of a transport packet
+pub const TRANSPORT_HEADER_LENGTH: usize =
+    VARIANT_LENGTH + SESSION_ID_LENGTH + COUNTER_LENGTH + LENGTH_LENGTH;
+/// Variant, sequence number and frame length before the frame of a data message
+const DATA_HEADER_LENGTH: usize = VARIANT_LENGTH + SEQUENCE_LENGTH + LENGTH_LENGTH;
+
+/// Plaintext of a `Message::Data`, encode it once and seal it for every recipient
+/// with [`seal_data_datagram`]
+pub fn encode_data(ethernet_frame: &[u8]) -> Vec<u8> {
+    let mut plaintext = Vec::with_capacity(DATA_HEADER_LENGTH + ethernet_frame.len());
+    plaintext.extend_from_slice(&DATA_VARIANT.to_le_bytes());
+    plaintext.extend_from_slice(&[0; SEQUENCE_LENGTH]);
+    plaintext.extend_from_slice(&(ethernet_frame.len() as u64).to_le_bytes());
+    plaintext.extend_from_slice(ethernet_frame);
+    plaintext
+}
+
+/// Borrow the sequence number and ethernet frame of a `Message::Data` plaintext,
+/// `None` for other messages
+pub fn parse_data(plaintext: &[u8]) -> Option<(u64, &[u8])> {
+    if plaintext.get(..VARIANT_LENGTH)? != DATA_VARIANT.to_le_bytes() {
+        return None;
+    }
+    let header = plaintext.get(..DATA_HEADER_LENGTH)?;
+    let read_u64 = |start: usize| u64::from_le_bytes(header[start..start + 8].try_into().unwrap());
+    let sequence = read_u64(VARIANT_LENGTH);
+    let length = read_u64(VARIANT_LENGTH + SEQUENCE_LENGTH);
+    let ethernet_frame = &plaintext[DATA_HEADER_LENGTH..];
+    (ethernet_frame.len() as u64 == length).then_some((sequence, ethernet_frame))
+}
+
+/// Whole datagram of a `Packet::Transport`, encrypted in place without intermediate buffers
+pub fn seal_datagram(session: &Session, plaintext: &[u8]) -> Vec<u8> {
+    seal(session, plaintext, None)
+}
+
+/// Like [`seal_datagram`] for a data message encoded once for every recipient,
+/// with the recipient's `sequence` number written into it
+pub fn seal_data_datagram(session: &Session, plaintext: &[u8], sequence: u64) -> Vec<u8> {
+    seal(session, plaintext, Some(sequence))
+}
+
+fn seal(session: &Session, plaintext: &[u8], sequence: Option<u64>) -> Vec<u8> {
+    let mut datagram =
+        Vec::with_capacity(HEADER_LENGTH + TRANSPORT_HEADER_LENGTH + plaintext.len() + TAG_LENGTH);
+    datagram.extend_from_slice(&MAGIC);
+    datagram.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    datagram.extend_from_slice(&TRANSPORT_VARIANT.to_le_bytes());
+    datagram.extend_from_slice(&session.id().to_le_bytes());
+    let counter_start = datagram.len();
+    datagram.extend_from_slice(&[0; COUNTER_LENGTH]);
+    datagram.extend_from_slice(&((plaintext.len() + TAG_LENGTH) as u64).to_le_bytes());
+    let plaintext_start = datagram.len();
+    datagram.extend_from_slice(plaintext);
+    if let Some(sequence) = sequence {
+        let sequence_start = plaintext_start + VARIANT_LENGTH;
+        datagram[sequence_start..sequence_start + SEQUENCE_LENGTH]
+            .copy_from_slice(&sequence.to_le_bytes());
+    }
+    let (counter, tag) = session.seal_in_place(&mut datagram[plaintext_start..]);
+    datagram[counter_start..plaintext_start - LENGTH_LENGTH]
+        .copy_from_slice(&counter.to_le_bytes());
+    datagram.extend_from_slice(&tag);
+    datagram
+}
+
+pub(crate) fn is_transport(packet: &[u8]) -> bool {
+    packet.get(..VARIANT_LENGTH) == Some(TRANSPORT_VARIANT.to_le_bytes().as_slice())
+}
+
+/// Session ID and counter of a `Packet::Transport` without the protocol header, its ciphertext
+/// starts after `TRANSPORT_HEADER_LENGTH` bytes, `None` if the length doesn't match
+pub fn parse_transport(packet: &[u8]) -> Option<(SessionId, u64)> {
+    if !is_transport(packet) || packet.len() < TRANSPORT_HEADER_LENGTH {
+        return None;
+    }
+    let read_u64 = |start: usize| u64::from_le_bytes(packet[start..start + 8].try_into().unwrap());
+    let session_id = read_u64(VARIANT_LENGTH);
+    let counter = read_u64(VARIANT_LENGTH + SESSION_ID_LENGTH);
+    let length = read_u64(VARIANT_LENGTH + SESSION_ID_LENGTH + COUNTER_LENGTH);
+    ((packet.len() - TRANSPORT_HEADER_LENGTH) as u64 == length).then_some((session_id, counter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bincode_options, crypto::peer_session, decode_message, encode_message, Message, Packet,
+    };
+    use bincode::Options;
+
+    const SESSION_ID: SessionId = 0x0102_0304_0506_0708;
+    const ETHERNET_FRAME: &[u8] = &[0xab; 60];
+
+    /// Sessions of both ends of a path
+    fn sessions() -> (Session, Session) {
+        let key = [7; 32];
+        (
+            peer_session(&key, true, SESSION_ID),
+            peer_session(&key, false, SESSION_ID),
+        )
+    }
+
+    #[test]
+    fn sealed_data_is_what_bincode_makes_of_transport_and_data() {
+        let (sender, receiver) = sessions();
+        let datagram = seal_data_datagram(&sender, &encode_data(ETHERNET_FRAME), 42);
+        assert_eq!(datagram[..MAGIC.len()], MAGIC);
+        let Packet::Transport {
+            session_id,
+            counter,
+            ciphertext,
+        } = bincode_options()
+            .deserialize(&datagram[HEADER_LENGTH..])
+            .unwrap()
+        else {
+            panic!("not a transport packet");
+        };
+        assert_eq!(session_id, SESSION_ID);
+        let plaintext = receiver.open(counter, &ciphertext).unwrap();
+        let Message::Data {
+            sequence,
+            ethernet_frame,
+        } = decode_message(&plaintext).unwrap()
+        else {
+            panic!("not a data message");
+        };
+        assert_eq!(sequence, 42);
+        assert_eq!(ethernet_frame, ETHERNET_FRAME);
+    }
+
+    #[test]
+    fn sealed_messages_are_what_bincode_makes_of_transport() {
+        let (sender, receiver) = sessions();
+        let datagram = seal_datagram(&sender, &encode_message(&Message::Ping));
+        let packet: Packet = bincode_options()
+            .deserialize(&datagram[HEADER_LENGTH..])
+            .unwrap();
+        let Packet::Transport {
+            counter,
+            ciphertext,
+            ..
+        } = packet
+        else {
+            panic!("not a transport packet");
+        };
+        let plaintext = receiver.open(counter, &ciphertext).unwrap();
+        assert!(matches!(decode_message(&plaintext), Ok(Message::Ping)));
+    }
+
+    #[test]
+    fn bincode_data_messages_are_parsed() {
+        let plaintext = encode_message(&Message::Data {
+            sequence: 42,
+            ethernet_frame: ETHERNET_FRAME.to_vec(),
+        });
+        assert_eq!(parse_data(&plaintext), Some((42, ETHERNET_FRAME)));
+        // Data messages are encoded with sequence number 0, it's written in when they're sealed
+        let unsequenced = encode_message(&Message::Data {
+            sequence: 0,
+            ethernet_frame: ETHERNET_FRAME.to_vec(),
+        });
+        assert_eq!(encode_data(ETHERNET_FRAME), unsequenced);
+        assert_eq!(parse_data(&encode_message(&Message::Ping)), None);
+        assert_eq!(parse_data(&plaintext[..plaintext.len() - 1]), None);
+    }
+
+    #[test]
+    fn bincode_transport_packets_are_parsed() {
+        let ciphertext = vec![0xcd; 80];
+        let packet = bincode_options()
+            .serialize(&Packet::Transport {
+                session_id: SESSION_ID,
+                counter: 9,
+                ciphertext: ciphertext.clone(),
+            })
+            .unwrap();
+        assert_eq!(parse_transport(&packet), Some((SESSION_ID, 9)));
+        assert_eq!(packet[TRANSPORT_HEADER_LENGTH..], ciphertext);
+        assert_eq!(parse_transport(&packet[..packet.len() - 1]), None);
+        let other_packet = bincode_options()
+            .serialize(&Packet::AddressRequest { padding: [0; 32] })
+            .unwrap();
+        assert!(!is_transport(&other_packet));
+    }
+}
@@ -0,0 +1,233 @@
+use crate::{
+    crypto::TAG_LENGTH,
+    data::{encode_data, SEQUENCE_LENGTH, TRANSPORT_HEADER_LENGTH},
+    encode_message, Message, HEADER_LENGTH, MAX_FRAME_LENGTH,
+};
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Datagrams stay within the IPv6 minimum MTU of 1280 bytes minus the IPv6 and UDP headers,
+/// so they fit through PPPoE, IPv6-in-IPv4 and other tunnels without getting IP fragmented.
+/// It's the conservative default for paths until their MTU is probed
+pub const MAX_DATAGRAM_LENGTH: usize = 1232;
+/// Longest plaintext that still fits in a [`MAX_DATAGRAM_LENGTH`] datagram
+pub const MAX_PLAINTEXT_LENGTH: usize =
+    MAX_DATAGRAM_LENGTH - HEADER_LENGTH - TRANSPORT_HEADER_LENGTH - TAG_LENGTH;
+/// Variant, sequence number, frame ID, index, count and payload length
+/// of an encoded `Message::Fragment`
+const FRAGMENT_HEADER_LENGTH: usize = 4 + SEQUENCE_LENGTH + 4 + 1 + 1 + 8;
+const MAX_PAYLOAD_LENGTH: usize = MAX_PLAINTEXT_LENGTH - FRAGMENT_HEADER_LENGTH;
+/// Enough for jumbo frames of 9000 bytes
+pub const MAX_FRAGMENTS: u8 = 16;
+/// Frames still missing fragments after this are dropped
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(3);
+/// Incomplete frames kept per sender, the oldest one is dropped to make room
+const MAX_PENDING_FRAMES: usize = 32;
+
+static NEXT_FRAME_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Plaintexts to send an ethernet frame with, one `Message::Data` if it fits in a datagram
+/// or `Message::Fragment`s if it doesn't, encode them once and seal them for every recipient
+/// with [`crate::data::seal_data_datagram`]
+pub fn encode_frame(ethernet_frame: &[u8]) -> Vec<Vec<u8>> {
+    encode_frame_within(ethernet_frame, MAX_PLAINTEXT_LENGTH)
+}
+
+/// Like [`encode_frame`] for a path probed to carry plaintexts up to `max_plaintext_length`,
+/// fragments stay small enough for any path since that's all the receiver takes
+pub fn encode_frame_within(ethernet_frame: &[u8], max_plaintext_length: usize) -> Vec<Vec<u8>> {
+    let plaintext = encode_data(ethernet_frame);
+    if plaintext.len() <= max_plaintext_length {
+        return vec![plaintext];
+    }
+    let frame_id = NEXT_FRAME_ID.fetch_add(1, Ordering::Relaxed);
+    let payloads = ethernet_frame.chunks(MAX_PAYLOAD_LENGTH);
+    let count = payloads.len() as u8;
+    payloads
+        .enumerate()
+        .map(|(index, payload)| {
+            encode_message(&Message::Fragment {
+                sequence: 0,
+                frame_id,
+                index: index as u8,
+                count,
+                payload: payload.to_vec(),
+            })
+        })
+        .collect()
+}
+
+struct PartialFrame {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    /// Bytes of the fragments received so far
+    length: usize,
+    started_at: Instant,
+}
+
+/// Puts fragmented frames from one sender back together
+#[derive(Default)]
+pub struct Reassembler {
+    frames: HashMap<u32, PartialFrame>,
+}
+
+impl Reassembler {
+    /// Returns the whole frame once its last fragment arrived, invalid fragments are ignored
+    /// and frames longer than [`MAX_FRAME_LENGTH`] dropped as soon as their fragments add up to it
+    pub fn add(
+        &mut self,
+        frame_id: u32,
+        index: u8,
+        count: u8,
+        payload: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        if !(2..=MAX_FRAGMENTS).contains(&count)
+            || index >= count
+            || payload.len() > MAX_PAYLOAD_LENGTH
+        {
+            return None;
+        }
+        // The rest of it is never coming, or the ID wrapped around
+        if self
+            .frames
+            .get(&frame_id)
+            .is_some_and(|frame| frame.started_at.elapsed() >= REASSEMBLY_TIMEOUT)
+        {
+            self.frames.remove(&frame_id);
+        }
+        if !self.frames.contains_key(&frame_id) {
+            self.make_room();
+        }
+        let frame = self.frames.entry(frame_id).or_insert_with(|| PartialFrame {
+            fragments: vec![None; count as usize],
+            received: 0,
+            length: 0,
+            started_at: Instant::now(),
+        });
+        if frame.fragments.len() != count as usize {
+            return None;
+        }
+        let fragment = &mut frame.fragments[index as usize];
+        if fragment.is_none() {
+            frame.length += payload.len();
+            *fragment = Some(payload);
+            frame.received += 1;
+        }
+        if frame.length > MAX_FRAME_LENGTH {
+            self.frames.remove(&frame_id);
+            return None;
+        }
+        if frame.received < frame.fragments.len() {
+            return None;
+        }
+        let frame = self.frames.remove(&frame_id).unwrap();
+        Some(frame.fragments.into_iter().flatten().flatten().collect())
+    }
+
+    fn make_room(&mut self) {
+        if self.frames.len() < MAX_PENDING_FRAMES {
+            return;
+        }
+        self.frames
+            .retain(|_, frame| frame.started_at.elapsed() < REASSEMBLY_TIMEOUT);
+        if self.frames.len() < MAX_PENDING_FRAMES {
+            return;
+        }
+        let oldest = self
+            .frames
+            .iter()
+            .min_by_key(|(_, frame)| frame.started_at)
+            .map(|(frame_id, _)| *frame_id)
+            .unwrap();
+        self.frames.remove(&oldest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_message, Opened};
+
+    /// What the receiver gets out of the plaintexts, fragments in reverse order
+    fn reassemble(plaintexts: &[Vec<u8>]) -> Option<Vec<u8>> {
+        let mut reassembler = Reassembler::default();
+        let mut frame = None;
+        for plaintext in plaintexts.iter().rev() {
+            match Opened::decode(plaintext).unwrap() {
+                Opened::Data { ethernet_frame, .. } => return Some(ethernet_frame.to_vec()),
+                Opened::Message(Message::Fragment {
+                    frame_id,
+                    index,
+                    count,
+                    payload,
+                    ..
+                }) => {
+                    assert!(frame.is_none());
+                    frame = reassembler.add(frame_id, index, count, payload);
+                }
+                _ => panic!("not a data message"),
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn small_frames_are_not_fragmented() {
+        let ethernet_frame = vec![7; 1000];
+        let plaintexts = encode_frame(&ethernet_frame);
+        assert_eq!(plaintexts.len(), 1);
+        assert_eq!(reassemble(&plaintexts), Some(ethernet_frame));
+    }
+
+    #[test]
+    fn jumbo_frames_are_put_back_together() {
+        let ethernet_frame: Vec<u8> = (0..MAX_FRAME_LENGTH).map(|byte| byte as u8).collect();
+        let plaintexts = encode_frame(&ethernet_frame);
+        assert!(plaintexts.len() > 1 && plaintexts.len() <= MAX_FRAGMENTS as usize);
+        assert!(plaintexts
+            .iter()
+            .all(|plaintext| plaintext.len() <= MAX_PLAINTEXT_LENGTH));
+        assert_eq!(reassemble(&plaintexts), Some(ethernet_frame));
+    }
+
+    #[test]
+    fn probed_paths_take_bigger_frames_whole() {
+        let ethernet_frame = vec![7; 1400];
+        assert!(encode_frame(&ethernet_frame).len() > 1);
+        assert_eq!(encode_frame_within(&ethernet_frame, 1500).len(), 1);
+    }
+
+    #[test]
+    fn frames_longer_than_the_maximum_are_dropped() {
+        let mut reassembler = Reassembler::default();
+        let mut dropped_at = None;
+        for index in 0..MAX_FRAGMENTS {
+            let payload = vec![0; MAX_PAYLOAD_LENGTH];
+            assert_eq!(reassembler.add(1, index, MAX_FRAGMENTS, payload), None);
+            if dropped_at.is_none() && !reassembler.frames.contains_key(&1) {
+                dropped_at = Some(index);
+            }
+        }
+        assert_eq!(
+            dropped_at,
+            Some((MAX_FRAME_LENGTH / MAX_PAYLOAD_LENGTH) as u8)
+        );
+    }
+
+    #[test]
+    fn duplicate_and_invalid_fragments_are_ignored() {
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.add(1, 0, 1, vec![0; 10]), None);
+        assert_eq!(reassembler.add(1, 2, 2, vec![0; 10]), None);
+        assert_eq!(reassembler.add(1, 0, 2, vec![1; 10]), None);
+        assert_eq!(reassembler.add(1, 0, 2, vec![2; 10]), None);
+        // A fragment of the same frame that disagrees on the count
+        assert_eq!(reassembler.add(1, 1, 3, vec![3; 10]), None);
+        let frame = reassembler.add(1, 1, 2, vec![3; 10]).unwrap();
+        assert_eq!(frame, [vec![1; 10], vec![3; 10]].concat());
+        assert!(decode_message(&encode_frame(&frame)[0]).is_ok());
+    }
+}
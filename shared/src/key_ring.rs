@@ -0,0 +1,160 @@
+use crate::{
+    accept,
+    crypto::{DecryptError, Session},
+    OpenError, Opened,
+};
+use std::time::{Duration, Instant};
+
+/// How long the previous session is still accepted after rekeying,
+/// so packets sent before the other side switched keys aren't dropped
+pub const KEY_OVERLAP: Duration = Duration::from_secs(30);
+
+/// Sessions with one peer while rekeying
+#[derive(Default)]
+pub struct KeyRing {
+    current: Option<Session>,
+    previous: Option<(Session, Instant)>,
+    /// Session from a handshake we responded to, we keep sending with the current one
+    /// until the peer uses it, since we can't be sure it got our handshake response before that
+    next: Option<Session>,
+}
+
+impl KeyRing {
+    /// Session to send with
+    pub fn current(&self) -> Option<&Session> {
+        self.current.as_ref()
+    }
+
+    /// Switch to a session we know the peer has, keeping the current one for a while
+    pub fn rotate(&mut self, session: Session) {
+        if let Some(current) = self.current.replace(session) {
+            self.previous = Some((current, Instant::now()));
+        }
+    }
+
+    /// Keep a session from a handshake we responded to until the peer starts to use it
+    pub fn set_next(&mut self, session: Session) {
+        self.next = Some(session);
+    }
+
+    /// False once the peer started using the next session and it became the current one
+    pub fn has_next(&self) -> bool {
+        self.next.is_some()
+    }
+
+    /// Decrypt in place with whichever session the peer used
+    pub fn open<'a>(
+        &mut self,
+        counter: u64,
+        ciphertext: &'a mut [u8],
+    ) -> Result<Opened<'a>, OpenError> {
+        if let Some((_, rotated_at)) = &self.previous {
+            if rotated_at.elapsed() >= KEY_OVERLAP {
+                self.previous = None;
+            }
+        }
+        if self.current.is_none() && self.next.is_none() {
+            return Err(OpenError::NoSession);
+        }
+        // A failed decryption leaves the ciphertext untouched for the next session to try,
+        // the sessions in use are tried first since the next one only gets used once
+        let opened = [
+            self.current.as_ref(),
+            self.previous.as_ref().map(|(session, _)| session),
+        ]
+        .into_iter()
+        .flatten()
+        .find_map(|session| {
+            let plaintext_length = session.open_in_place(counter, ciphertext).ok()?;
+            Some((session, plaintext_length))
+        });
+        if let Some((session, plaintext_length)) = opened {
+            return accept(session, counter, &ciphertext[..plaintext_length]);
+        }
+        let plaintext_length = self
+            .next
+            .as_ref()
+            .ok_or(OpenError::Decrypt(DecryptError))?
+            .open_in_place(counter, ciphertext)
+            .map_err(OpenError::Decrypt)?;
+        let next = self.next.take().unwrap();
+        self.rotate(next);
+        accept(
+            self.current.as_ref().unwrap(),
+            counter,
+            &ciphertext[..plaintext_length],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::{next_peer_key, peer_session},
+        encode_message, Message,
+    };
+
+    fn ping(session: &Session) -> (u64, Vec<u8>) {
+        session.seal(&encode_message(&Message::Ping))
+    }
+
+    fn opens(keys: &mut KeyRing, (counter, mut ciphertext): (u64, Vec<u8>)) -> bool {
+        matches!(
+            keys.open(counter, &mut ciphertext),
+            Ok(Opened::Message(Message::Ping))
+        )
+    }
+
+    #[test]
+    fn moves_on_to_the_next_session_once_the_peer_uses_it() {
+        let key = [7; 32];
+        let next_key = next_peer_key(&key);
+        let peer_current = peer_session(&key, true, 1);
+        let peer_next = peer_session(&next_key, true, 1);
+        let mut keys = KeyRing::default();
+        keys.rotate(peer_session(&key, false, 1));
+        keys.set_next(peer_session(&next_key, false, 1));
+
+        assert!(opens(&mut keys, ping(&peer_current)));
+        assert!(keys.has_next());
+        assert!(opens(&mut keys, ping(&peer_next)));
+        assert!(!keys.has_next());
+        // Still on the way from before the peer switched
+        assert!(opens(&mut keys, ping(&peer_current)));
+        // We send with the new one, which the peer can open
+        let mut peer_keys = KeyRing::default();
+        peer_keys.rotate(peer_next);
+        assert!(opens(&mut peer_keys, ping(keys.current().unwrap())));
+    }
+
+    #[test]
+    fn rejects_sessions_it_does_not_have() {
+        let mut keys = KeyRing::default();
+        let stranger = peer_session(&[1; 32], true, 1);
+        let (counter, mut ciphertext) = ping(&stranger);
+        assert!(matches!(
+            keys.open(counter, &mut ciphertext),
+            Err(OpenError::NoSession)
+        ));
+        keys.rotate(peer_session(&[2; 32], false, 1));
+        assert!(matches!(
+            keys.open(counter, &mut ciphertext),
+            Err(OpenError::Decrypt(_))
+        ));
+    }
+
+    #[test]
+    fn replays_are_rejected() {
+        let key = [7; 32];
+        let peer = peer_session(&key, true, 1);
+        let mut keys = KeyRing::default();
+        keys.rotate(peer_session(&key, false, 1));
+        let packet = ping(&peer);
+        assert!(opens(&mut keys, packet.clone()));
+        assert!(matches!(
+            keys.open(packet.0, &mut packet.1.clone()),
+            Err(OpenError::Replay)
+        ));
+    }
+}
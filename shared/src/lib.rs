@@ -1,18 +1,211 @@
+use auth::Proof;
+use bincode::Options;
 use chrono::Local;
+use crypto::{DecryptError, Session};
 use macaddr::MacAddr6;
+use peer_list::PeerInfo;
 use serde::{Deserialize, Serialize};
-use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::{
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::atomic::{AtomicU64, Ordering},
+};
 
+pub mod auth;
+pub mod batch;
+pub mod compression;
+pub mod cookie;
+pub mod crypto;
+pub mod data;
+pub mod fragment;
+pub mod key_ring;
+pub mod peer_list;
+pub mod pmtu;
+pub mod replay;
+pub mod sequence;
+
+/// Every datagram starts with this, anything else isn't for us
+pub const MAGIC: [u8; 4] = *b"SPVN";
+/// Bumped on any incompatible change to [`Packet`] or [`Message`],
+/// clients and servers only talk to the same version
+pub const PROTOCOL_VERSION: u16 = 3;
+
+/// Why we can't talk to a server that answered with [`Packet::VersionMismatch`]
+pub fn version_mismatch_reason(server_version: u16) -> String {
+    format!("Server uses protocol version {server_version} but client uses {PROTOCOL_VERSION}")
+}
+/// Optional features this build supports, one bit each, negotiated with [`Message::Hello`]
+pub const CAPABILITIES: u32 =
+    CAPABILITY_COMPRESSION | CAPABILITY_BATCHING | CAPABILITY_DIRECT | CAPABILITY_PEER_LIST;
+/// Data frames can be sent as [`Message::CompressedData`]
+pub const CAPABILITY_COMPRESSION: u32 = 1 << 0;
+/// Small data frames can be sent together as a [`Message::Batch`]
+pub const CAPABILITY_BATCHING: u32 = 1 << 1;
+/// Clients can be introduced to each other with [`Message::PeerIntroduction`]
+pub const CAPABILITY_DIRECT: u32 = 1 << 2;
+/// The server pushes the registered clients with [`Message::PeerList`]
+pub const CAPABILITY_PEER_LIST: u32 = 1 << 3;
+const HEADER_LENGTH: usize = MAGIC.len() + 2;
+/// Longest ethernet frame we take, a jumbo frame of 9000 bytes with an ethernet and a VLAN header
+pub const MAX_FRAME_LENGTH: usize = 9000 + 14 + 4;
+/// Longest reason a [`Message::RegisterFail`] or [`Message::Kick`] can give
+pub const MAX_REASON_LENGTH: usize = 256;
+
+/// Identifies a session on the server, the client keeps it through rekeys
+pub type SessionId = u64;
+
+/// What actually goes on the wire after the header, everything after the handshake is encrypted,
+/// new variants only go at the end so other versions can still decode the handshake
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Packet {
+    /// Without a valid cookie the server only answers with [`Packet::Cookie`]
+    HandshakeInit {
+        public_key: [u8; 32],
+        cookie: Option<[u8; 16]>,
+    },
+    Cookie {
+        cookie: [u8; 16],
+    },
+    HandshakeResponse {
+        public_key: [u8; 32],
+        static_public_key: [u8; 32],
+        confirmation: [u8; 16],
+        session_id: SessionId,
+    },
+    Transport {
+        session_id: SessionId,
+        counter: u64,
+        ciphertext: Vec<u8>,
+    },
+    /// Asks which address the server sees us at, padded so the answer is never bigger
+    /// and can't be used for amplification
+    AddressRequest {
+        padding: [u8; 32],
+    },
+    AddressResponse {
+        mapped_address: SocketAddr,
+        /// Second port of the server to ask again on, the NAT type shows in whether
+        /// it sees the same address
+        probe_port: Option<u16>,
+    },
+    /// Answer to a [`Packet::HandshakeInit`] from another protocol version, its variant index
+    /// and layout never change so every version can tell why the server won't talk to it
+    VersionMismatch {
+        version: u16,
+    },
+}
+
+/// Messages encrypted inside [`Packet::Transport`], new variants only go at the end
+/// so [`Message::Hello`] and [`Message::RegisterFail`] decode on every version.
+/// Messages carrying ethernet frames start with a sequence number per sender and recipient,
+/// written in when sealing with [`data::seal_data_datagram`]
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message {
-    Register { mac_address: MacAddr6 },
-    RegisterSuccess { ip: Ipv4Addr, subnet_mask: Ipv4Addr },
-    RegisterFail { reason: String },
+    Register {
+        mac_address: MacAddr6,
+    },
+    Challenge {
+        nonce: [u8; 32],
+    },
+    ChallengeResponse {
+        proof: Proof,
+    },
+    RegisterSuccess {
+        ip: Ipv4Addr,
+        subnet_mask: Ipv4Addr,
+    },
+    RegisterFail {
+        reason: String,
+    },
     Ping,
     Pong,
-    Data { ethernet_frame: Vec<u8> },
+    /// Sent with [`data::encode_data`] and received as [`Opened::Data`] without serde
+    Data {
+        sequence: u64,
+        ethernet_frame: Vec<u8>,
+    },
+    /// First message after the handshake, the server answers with the capabilities both sides
+    /// support, the versions always match since other versions get a [`Packet::VersionMismatch`]
+    Hello {
+        version: u16,
+        capabilities: u32,
+    },
+    /// New handshake for the current session, authenticated by the keys being replaced,
+    /// the server keeps using them until the client uses the new ones
+    Rekey {
+        public_key: [u8; 32],
+    },
+    RekeyResponse {
+        public_key: [u8; 32],
+        confirmation: [u8; 16],
+    },
+    /// The client is leaving, the server frees its IP right away
+    Disconnect,
+    /// The server dropped the client, it shouldn't try to reconnect
+    Kick {
+        reason: String,
+    },
+    /// The server is going away, reconnecting won't work until it's back
+    ServerShutdown,
+    /// New network settings for a registered client
+    ConfigUpdate {
+        ip: Ipv4Addr,
+        subnet_mask: Ipv4Addr,
+    },
+    /// Part of an ethernet frame too big for one datagram, see [`fragment::encode_frame`]
+    Fragment {
+        sequence: u64,
+        frame_id: u32,
+        index: u8,
+        count: u8,
+        payload: Vec<u8>,
+    },
+    /// LZ4 compressed ethernet frame, only sent if both sides have [`CAPABILITY_COMPRESSION`],
+    /// see [`compression::encode_compressed`]
+    CompressedData {
+        sequence: u64,
+        frame_length: u32,
+        compressed_frame: Vec<u8>,
+    },
+    /// Small ethernet frames sent in one datagram, only sent if both sides have
+    /// [`CAPABILITY_BATCHING`], see [`batch::Batcher`]
+    Batch {
+        sequence: u64,
+        ethernet_frames: Vec<Vec<u8>>,
+    },
+    /// Ask the server for a direct path to the client with this MAC address
+    PeerRequest {
+        mac_address: MacAddr6,
+    },
+    /// Sent to both clients of a direct path, with the endpoint the server sees the other one at
+    /// and a key for a session between them, they punch through their NATs by pinging each other
+    /// with it and only send data on it once they got a pong
+    PeerIntroduction {
+        mac_address: MacAddr6,
+        endpoint: SocketAddr,
+        session_id: SessionId,
+        key: [u8; 32],
+        initiator: bool,
+    },
+    /// Page of the registered clients, pushed when one joins, leaves or moves,
+    /// see [`peer_list::encode_peer_list`]
+    PeerList {
+        /// Bumped every time the server pushes the list
+        generation: u64,
+        index: u8,
+        count: u8,
+        peers: Vec<PeerInfo>,
+    },
+    /// Padded to the datagram length being tried on a path, see [`pmtu::probe`]
+    MtuProbe {
+        padding: Vec<u8>,
+    },
+    /// The [`Message::MtuProbe`] sent in a datagram this long got through
+    MtuProbeAck {
+        datagram_length: u32,
+    },
 }
 
+#[allow(clippy::result_unit_err)]
 pub fn get_mac_addresses(ethernet_frame: &[u8]) -> Result<(MacAddr6, MacAddr6), ()> {
     if ethernet_frame.len() < 12 {
         return Err(());
@@ -23,26 +216,357 @@ pub fn get_mac_addresses(ethernet_frame: &[u8]) -> Result<(MacAddr6, MacAddr6),
     Ok((source_mac_address.into(), destination_mac_address.into()))
 }
 
-pub fn send(socket: &UdpSocket, message: &Message) {
-    let payload = &bincode::serialize(message).unwrap();
-    let mut bytes_written = 0;
-    while bytes_written < payload.len() {
-        bytes_written += socket.send(payload).unwrap();
+pub fn encode_message(message: &Message) -> Vec<u8> {
+    bincode::serialize(message).unwrap()
+}
+
+/// Decodes what `bincode::serialize` encodes, without trailing bytes, and no length prefix
+/// can claim more than a datagram holds, so nothing big gets allocated for garbage
+fn bincode_options() -> impl Options {
+    bincode::options()
+        .with_fixint_encoding()
+        .with_limit(RECEIVE_BUFFER_SIZE as u64)
+}
+
+#[derive(Debug)]
+pub enum MessageError {
+    /// Not a message, or a length prefix longer than the datagram
+    Malformed(bincode::Error),
+    FrameTooLong(usize),
+    ReasonTooLong(usize),
+    /// Index not below the count, or a count of fragments we'd never send
+    InvalidFragment,
+    /// Index not below the count, or more pages than a list can have
+    InvalidPeerList,
+}
+
+impl std::fmt::Display for MessageError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MessageError::Malformed(error) => write!(formatter, "malformed message: {error}"),
+            MessageError::FrameTooLong(length) => write!(
+                formatter,
+                "{length} bytes ethernet frame, longer than {MAX_FRAME_LENGTH} bytes"
+            ),
+            MessageError::ReasonTooLong(length) => write!(
+                formatter,
+                "{length} bytes reason, longer than {MAX_REASON_LENGTH} bytes"
+            ),
+            MessageError::InvalidFragment => write!(formatter, "invalid fragment"),
+            MessageError::InvalidPeerList => write!(formatter, "invalid peer list"),
+        }
     }
-    // let bytes_written = socket.send(payload).unwrap();
-    // if bytes_written < payload.len() {
-    //     log!(
-    //         "should send {} bytes but only {bytes_written} bytes sent",
-    //         payload.len()
-    //     );
-    // }
 }
 
-pub fn send_to(socket: &UdpSocket, message: &Message, to_address: &SocketAddr) {
-    let payload = &bincode::serialize(message).unwrap();
+/// Decode a message with bincode and check the lengths in it
+pub fn decode_message(plaintext: &[u8]) -> Result<Message, MessageError> {
+    let message: Message = bincode_options()
+        .deserialize(plaintext)
+        .map_err(MessageError::Malformed)?;
+    match &message {
+        Message::RegisterFail { reason } | Message::Kick { reason }
+            if reason.len() > MAX_REASON_LENGTH =>
+        {
+            Err(MessageError::ReasonTooLong(reason.len()))
+        }
+        Message::Data { ethernet_frame, .. } if ethernet_frame.len() > MAX_FRAME_LENGTH => {
+            Err(MessageError::FrameTooLong(ethernet_frame.len()))
+        }
+        Message::CompressedData { frame_length, .. }
+            if *frame_length as usize > MAX_FRAME_LENGTH =>
+        {
+            Err(MessageError::FrameTooLong(*frame_length as usize))
+        }
+        Message::Batch {
+            ethernet_frames, ..
+        } => match ethernet_frames
+            .iter()
+            .find(|ethernet_frame| ethernet_frame.len() > MAX_FRAME_LENGTH)
+        {
+            Some(ethernet_frame) => Err(MessageError::FrameTooLong(ethernet_frame.len())),
+            None => Ok(message),
+        },
+        Message::Fragment { index, count, .. }
+            if !(2..=fragment::MAX_FRAGMENTS).contains(count) || index >= count =>
+        {
+            Err(MessageError::InvalidFragment)
+        }
+        Message::PeerList { index, count, .. }
+            if !(1..=peer_list::MAX_PAGES).contains(count) || index >= count =>
+        {
+            Err(MessageError::InvalidPeerList)
+        }
+        _ => Ok(message),
+    }
+}
+
+/// A decrypted [`Packet::Transport`], data frames are borrowed from the receive buffer
+pub enum Opened<'a> {
+    Data {
+        sequence: u64,
+        ethernet_frame: &'a [u8],
+    },
+    Message(Message),
+}
+
+impl<'a> Opened<'a> {
+    pub fn decode(plaintext: &'a [u8]) -> Result<Self, MessageError> {
+        match data::parse_data(plaintext) {
+            Some((_, ethernet_frame)) if ethernet_frame.len() > MAX_FRAME_LENGTH => {
+                Err(MessageError::FrameTooLong(ethernet_frame.len()))
+            }
+            Some((sequence, ethernet_frame)) => Ok(Opened::Data {
+                sequence,
+                ethernet_frame,
+            }),
+            None => decode_message(plaintext).map(Opened::Message),
+        }
+    }
+
+    /// Sequence number of a message carrying ethernet frames
+    pub fn data_sequence(&self) -> Option<u64> {
+        match self {
+            Opened::Data { sequence, .. }
+            | Opened::Message(
+                Message::Fragment { sequence, .. }
+                | Message::CompressedData { sequence, .. }
+                | Message::Batch { sequence, .. },
+            ) => Some(*sequence),
+            Opened::Message(_) => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum OpenError {
+    Decrypt(DecryptError),
+    /// Counter already seen or too far behind
+    Replay,
+    /// No handshake done with this peer
+    NoSession,
+    Decode(MessageError),
+}
+
+/// Decrypt a [`Packet::Transport`] in place, nothing gets decoded if the decryption fails
+/// or it's a replay
+pub fn open<'a>(
+    session: &Session,
+    counter: u64,
+    ciphertext: &'a mut [u8],
+) -> Result<Opened<'a>, OpenError> {
+    let plaintext_length = session
+        .open_in_place(counter, ciphertext)
+        .map_err(OpenError::Decrypt)?;
+    accept(session, counter, &ciphertext[..plaintext_length])
+}
+
+/// Check the counter of a plaintext `session` decrypted and decode it
+fn accept<'a>(
+    session: &Session,
+    counter: u64,
+    plaintext: &'a [u8],
+) -> Result<Opened<'a>, OpenError> {
+    if !session.accept_counter(counter) {
+        return Err(OpenError::Replay);
+    }
+    Opened::decode(plaintext).map_err(OpenError::Decode)
+}
+
+pub fn send_to(socket: &UdpSocket, session: &Session, message: &Message, to_address: &SocketAddr) {
+    send_plaintext_to(socket, session, &encode_message(message), to_address);
+}
+
+/// Seal and send an already encoded message, like a data frame shared by several recipients
+pub fn send_plaintext_to(
+    socket: &UdpSocket,
+    session: &Session,
+    plaintext: &[u8],
+    to_address: &SocketAddr,
+) {
+    send_datagram_to(socket, &data::seal_datagram(session, plaintext), to_address);
+}
+
+/// Seal and send an already encoded data message with the recipient's sequence number
+pub fn send_data_plaintext_to(
+    socket: &UdpSocket,
+    session: &Session,
+    plaintext: &[u8],
+    sequence: u64,
+    to_address: &SocketAddr,
+) {
+    send_datagram_to(
+        socket,
+        &data::seal_data_datagram(session, plaintext, sequence),
+        to_address,
+    );
+}
+
+fn encode_packet(packet: &Packet) -> Vec<u8> {
+    let mut payload =
+        Vec::with_capacity(HEADER_LENGTH + bincode::serialized_size(packet).unwrap() as usize);
+    payload.extend_from_slice(&MAGIC);
+    payload.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut payload, packet).unwrap();
+    payload
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// Doesn't start with [`MAGIC`]
+    NotOurs,
+    /// A [`Packet::Transport`] whose length doesn't match
+    InvalidTransport,
+    /// Sent with another [`PROTOCOL_VERSION`], and not one of the packets every version reads
+    OtherVersion(u16),
+    Decode(bincode::Error),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DecodeError::NotOurs => write!(formatter, "not a VPN packet"),
+            DecodeError::InvalidTransport => write!(formatter, "invalid transport packet"),
+            DecodeError::OtherVersion(version) => write!(
+                formatter,
+                "sent with protocol version {version} but we use {PROTOCOL_VERSION}"
+            ),
+            DecodeError::Decode(error) => write!(formatter, "{error}"),
+        }
+    }
+}
+
+/// Datagrams [`receive_until_success`] dropped since they were last logged, counted instead of
+/// logged one by one so garbage sent at us can't flood the log
+#[derive(Default)]
+pub struct DecodeErrors {
+    not_ours: AtomicU64,
+    invalid_transport: AtomicU64,
+    other_version: AtomicU64,
+    malformed: AtomicU64,
+}
+
+impl DecodeErrors {
+    fn count(&self, error: &DecodeError) {
+        let counter = match error {
+            DecodeError::NotOurs => &self.not_ours,
+            DecodeError::InvalidTransport => &self.invalid_transport,
+            DecodeError::OtherVersion(_) => &self.other_version,
+            DecodeError::Decode(_) => &self.malformed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn log_and_reset(&self) {
+        let not_ours = self.not_ours.swap(0, Ordering::Relaxed);
+        if not_ours > 0 {
+            log!("Dropped {not_ours} datagrams that aren't VPN packets");
+        }
+        let invalid_transport = self.invalid_transport.swap(0, Ordering::Relaxed);
+        if invalid_transport > 0 {
+            log!("Dropped {invalid_transport} transport packets with a wrong length");
+        }
+        let other_version = self.other_version.swap(0, Ordering::Relaxed);
+        if other_version > 0 {
+            log!(
+                "Dropped {other_version} packets from other protocol versions, we use {PROTOCOL_VERSION}"
+            );
+        }
+        let malformed = self.malformed.swap(0, Ordering::Relaxed);
+        if malformed > 0 {
+            log!("Dropped {malformed} malformed packets");
+        }
+    }
+}
+
+/// A received datagram, transport packets are borrowed from the receive buffer
+pub enum Datagram<'a> {
+    Transport {
+        session_id: SessionId,
+        counter: u64,
+        /// Decrypted in place by [`open`]
+        ciphertext: &'a mut [u8],
+    },
+    Packet(Packet),
+    /// A [`Packet::HandshakeInit`] sent with another protocol version,
+    /// answer it with [`Packet::VersionMismatch`]
+    OtherVersionHandshake {
+        version: u16,
+    },
+}
+
+/// What [`decode_datagram`] makes of a datagram before it borrows the receive buffer
+#[doc(hidden)]
+pub enum Decoded {
+    Transport {
+        session_id: SessionId,
+        counter: u64,
+        ciphertext_start: usize,
+    },
+    Packet(Packet),
+    OtherVersionHandshake {
+        version: u16,
+    },
+}
+
+// Variant indexes of the packets every protocol version reads the same way,
+// a handshake init tells us the other side's version and the mismatch answer tells them ours
+const HANDSHAKE_INIT_VARIANT: u32 = 0;
+const VERSION_MISMATCH_VARIANT: u32 = 6;
+
+/// Everything received goes through this before being authenticated,
+/// only public so it can be fuzzed
+#[doc(hidden)]
+pub fn decode_datagram(payload: &[u8]) -> Result<Decoded, DecodeError> {
+    if payload.len() < HEADER_LENGTH || payload[..MAGIC.len()] != MAGIC {
+        return Err(DecodeError::NotOurs);
+    }
+    let version = u16::from_le_bytes([payload[MAGIC.len()], payload[MAGIC.len() + 1]]);
+    let packet = &payload[HEADER_LENGTH..];
+    if version != PROTOCOL_VERSION {
+        let variant = packet
+            .get(..4)
+            .map(|variant| u32::from_le_bytes(variant.try_into().unwrap()));
+        return match (variant, packet.get(4..)) {
+            // Every version's handshake init has at least a public key, so the answer is never
+            // bigger than the request and can't be used for amplification
+            (Some(HANDSHAKE_INIT_VARIANT), Some(rest)) if rest.len() >= 32 => {
+                Ok(Decoded::OtherVersionHandshake { version })
+            }
+            (Some(VERSION_MISMATCH_VARIANT), Some(&[low, high])) => {
+                Ok(Decoded::Packet(Packet::VersionMismatch {
+                    version: u16::from_le_bytes([low, high]),
+                }))
+            }
+            _ => Err(DecodeError::OtherVersion(version)),
+        };
+    }
+    if data::is_transport(packet) {
+        let (session_id, counter) =
+            data::parse_transport(packet).ok_or(DecodeError::InvalidTransport)?;
+        return Ok(Decoded::Transport {
+            session_id,
+            counter,
+            ciphertext_start: HEADER_LENGTH + data::TRANSPORT_HEADER_LENGTH,
+        });
+    }
+    bincode_options()
+        .deserialize(packet)
+        .map(Decoded::Packet)
+        .map_err(DecodeError::Decode)
+}
+
+pub fn send_packet_to(socket: &UdpSocket, packet: &Packet, to_address: &SocketAddr) {
+    send_datagram_to(socket, &encode_packet(packet), to_address);
+}
+
+/// Dropped if it can't be sent, like a peer's address being unreachable from here
+fn send_datagram_to(socket: &UdpSocket, payload: &[u8], to_address: &SocketAddr) {
     let mut bytes_written = 0;
     while bytes_written < payload.len() {
-        bytes_written += socket.send_to(payload, to_address).unwrap();
+        match socket.send_to(payload, to_address) {
+            Ok(written) => bytes_written += written,
+            Err(_) => return,
+        }
     }
     // let bytes_written = socket.send(payload).unwrap();
     // if bytes_written < payload.len() {
@@ -53,8 +577,11 @@ pub fn send_to(socket: &UdpSocket, message: &Message, to_address: &SocketAddr) {
     // }
 }
 
-pub struct ReceiveMessage {
-    pub message: Message,
+/// Big enough for any datagram we send
+pub const RECEIVE_BUFFER_SIZE: usize = 10000;
+
+pub struct ReceivePacket<'a> {
+    pub datagram: Datagram<'a>,
     pub source_address: SocketAddr,
 }
 
@@ -68,20 +595,43 @@ pub struct ReceiveMessage {
 //         })
 // }
 
-pub fn receive_until_success(socket: &UdpSocket) -> ReceiveMessage {
-    let mut buffer = [0; 10000];
+/// Receive into `buffer`, which transport packets keep borrowing to be decrypted in place,
+/// datagrams that can't be decoded are counted in `decode_errors`
+pub fn receive_until_success<'a>(
+    socket: &UdpSocket,
+    buffer: &'a mut [u8],
+    decode_errors: &DecodeErrors,
+) -> ReceivePacket<'a> {
     loop {
-        if let Ok((bytes_read, source_address)) = socket.recv_from(&mut buffer) {
-            match bincode::deserialize(&buffer[..bytes_read]) {
-                Ok(message) => {
-                    return ReceiveMessage {
-                        message,
+        if let Ok((bytes_read, source_address)) = socket.recv_from(buffer) {
+            match decode_datagram(&buffer[..bytes_read]) {
+                Ok(Decoded::Transport {
+                    session_id,
+                    counter,
+                    ciphertext_start,
+                }) => {
+                    return ReceivePacket {
+                        datagram: Datagram::Transport {
+                            session_id,
+                            counter,
+                            ciphertext: &mut buffer[ciphertext_start..bytes_read],
+                        },
                         source_address,
                     }
                 }
-                Err(error) => {
-                    log!("Can't decode packet with bincode, error: {error}");
+                Ok(Decoded::Packet(packet)) => {
+                    return ReceivePacket {
+                        datagram: Datagram::Packet(packet),
+                        source_address,
+                    }
+                }
+                Ok(Decoded::OtherVersionHandshake { version }) => {
+                    return ReceivePacket {
+                        datagram: Datagram::OtherVersionHandshake { version },
+                        source_address,
+                    }
                 }
+                Err(error) => decode_errors.count(&error),
             }
         }
         // else {
@@ -111,3 +661,102 @@ pub fn setup_panic_logging_hook() {
         default_panic(info);
     }));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_version(version: u16, packet: &Packet) -> Vec<u8> {
+        let mut datagram = encode_packet(packet);
+        datagram[MAGIC.len()..HEADER_LENGTH].copy_from_slice(&version.to_le_bytes());
+        datagram
+    }
+
+    #[test]
+    fn other_version_handshake_is_recognized() {
+        let datagram = with_version(
+            PROTOCOL_VERSION + 1,
+            &Packet::HandshakeInit {
+                public_key: [1; 32],
+                cookie: None,
+            },
+        );
+        assert!(matches!(
+            decode_datagram(&datagram),
+            Ok(Decoded::OtherVersionHandshake { version }) if version == PROTOCOL_VERSION + 1
+        ));
+        // Too short to be a handshake init of any version
+        assert!(matches!(
+            decode_datagram(&datagram[..HEADER_LENGTH + 4 + 31]),
+            Err(DecodeError::OtherVersion(_))
+        ));
+    }
+
+    #[test]
+    fn version_mismatch_decodes_on_every_version() {
+        let packet = Packet::VersionMismatch {
+            version: PROTOCOL_VERSION,
+        };
+        for receiver_version in [PROTOCOL_VERSION, PROTOCOL_VERSION + 1] {
+            let datagram = with_version(receiver_version, &packet);
+            assert!(matches!(
+                decode_datagram(&datagram),
+                Ok(Decoded::Packet(Packet::VersionMismatch { version })) if version == PROTOCOL_VERSION
+            ));
+        }
+        // Never bigger than the smallest handshake init it answers
+        assert!(encode_packet(&packet).len() <= HEADER_LENGTH + 4 + 32);
+    }
+
+    #[test]
+    fn other_version_handshake_gets_both_versions_back() {
+        let client_version = PROTOCOL_VERSION + 1;
+        let datagram = with_version(
+            client_version,
+            &Packet::HandshakeInit {
+                public_key: [1; 32],
+                cookie: None,
+            },
+        );
+        let Ok(Decoded::OtherVersionHandshake { version }) = decode_datagram(&datagram) else {
+            panic!("not recognized as a handshake");
+        };
+        assert_eq!(version, client_version);
+        assert!(matches!(
+            decode_datagram(&encode_packet(&Packet::VersionMismatch {
+                version: PROTOCOL_VERSION
+            })),
+            Ok(Decoded::Packet(Packet::VersionMismatch { version })) if version == PROTOCOL_VERSION
+        ));
+        // And the other way around, what a client gets from a server of another version
+        let server_version = PROTOCOL_VERSION + 1;
+        let answer = with_version(
+            server_version,
+            &Packet::VersionMismatch {
+                version: server_version,
+            },
+        );
+        let Ok(Decoded::Packet(Packet::VersionMismatch { version })) = decode_datagram(&answer)
+        else {
+            panic!("not a version mismatch");
+        };
+        assert_eq!(
+            version_mismatch_reason(version),
+            format!(
+                "Server uses protocol version {server_version} but client uses {PROTOCOL_VERSION}"
+            )
+        );
+    }
+
+    #[test]
+    fn other_version_packets_are_not_decoded() {
+        let datagram = with_version(
+            PROTOCOL_VERSION + 1,
+            &Packet::AddressRequest { padding: [0; 32] },
+        );
+        assert!(matches!(
+            decode_datagram(&datagram),
+            Err(DecodeError::OtherVersion(_))
+        ));
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{encode_message, fragment::MAX_PLAINTEXT_LENGTH, Message};
+use macaddr::MacAddr6;
+use serde::{Deserialize, Serialize};
+use std::net::{Ipv4Addr, SocketAddr};
+
+/// Enough for every address of the server's /24 subnet
+pub const MAX_PAGES: u8 = 32;
+/// Variant, generation, index, count and peer count of an encoded `Message::PeerList`
+const PEER_LIST_HEADER_LENGTH: usize = 4 + 8 + 1 + 1 + 8;
+
+/// A registered client as the server tells the others about it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PeerInfo {
+    pub mac_address: MacAddr6,
+    pub ip: Ipv4Addr,
+    /// Where the server sees it, only if it can take direct paths
+    pub endpoint: Option<SocketAddr>,
+}
+
+/// Plaintexts of the `Message::PeerList` pages with all `peers`, an empty list is one empty page
+pub fn encode_peer_list(generation: u64, peers: &[PeerInfo]) -> Vec<Vec<u8>> {
+    let mut pages = vec![Vec::new()];
+    let mut length = PEER_LIST_HEADER_LENGTH;
+    for peer in peers {
+        let peer_length = bincode::serialized_size(peer).unwrap() as usize;
+        if length + peer_length > MAX_PLAINTEXT_LENGTH {
+            pages.push(Vec::new());
+            length = PEER_LIST_HEADER_LENGTH;
+        }
+        pages.last_mut().unwrap().push(*peer);
+        length += peer_length;
+    }
+    let count = pages.len() as u8;
+    pages
+        .into_iter()
+        .enumerate()
+        .map(|(index, peers)| {
+            encode_message(&Message::PeerList {
+                generation,
+                index: index as u8,
+                count,
+                peers,
+            })
+        })
+        .collect()
+}
+
+/// Puts the pages of the peer list from the server back together
+#[derive(Default)]
+pub struct PeerListAssembler {
+    generation: u64,
+    pages: Vec<Option<Vec<PeerInfo>>>,
+}
+
+impl PeerListAssembler {
+    /// Returns the whole list once its last page arrived, pages of an older list than the one
+    /// being put together are ignored and a newer one replaces it
+    pub fn add(
+        &mut self,
+        generation: u64,
+        index: u8,
+        count: u8,
+        peers: Vec<PeerInfo>,
+    ) -> Option<Vec<PeerInfo>> {
+        if !(1..=MAX_PAGES).contains(&count) || index >= count || generation < self.generation {
+            return None;
+        }
+        if generation > self.generation || self.pages.len() != count as usize {
+            self.generation = generation;
+            self.pages = vec![None; count as usize];
+        }
+        self.pages[index as usize] = Some(peers);
+        if self.pages.iter().any(Option::is_none) {
+            return None;
+        }
+        let pages = std::mem::take(&mut self.pages);
+        Some(pages.into_iter().flatten().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode_message;
+
+    fn peers(count: u8) -> Vec<PeerInfo> {
+        (0..count)
+            .map(|index| PeerInfo {
+                mac_address: MacAddr6::new(2, 0, 0, 0, 0, index),
+                ip: Ipv4Addr::new(10, 123, 123, index),
+                endpoint: Some(SocketAddr::from(([203, 0, 113, index], 40000))),
+            })
+            .collect()
+    }
+
+    fn decode_page(page: &[u8]) -> (u64, u8, u8, Vec<PeerInfo>) {
+        assert!(page.len() <= MAX_PLAINTEXT_LENGTH);
+        match decode_message(page) {
+            Ok(Message::PeerList {
+                generation,
+                index,
+                count,
+                peers,
+            }) => (generation, index, count, peers),
+            _ => panic!("not a peer list"),
+        }
+    }
+
+    #[test]
+    fn empty_list_is_one_page() {
+        let pages = encode_peer_list(1, &[]);
+        assert_eq!(pages.len(), 1);
+        let (generation, index, count, peers) = decode_page(&pages[0]);
+        let mut assembler = PeerListAssembler::default();
+        assert_eq!(
+            assembler.add(generation, index, count, peers),
+            Some(Vec::new())
+        );
+    }
+
+    #[test]
+    fn pages_in_any_order_put_the_list_back_together() {
+        let all_peers = peers(253);
+        let pages = encode_peer_list(7, &all_peers);
+        assert!(pages.len() > 1 && pages.len() <= MAX_PAGES as usize);
+        let mut assembler = PeerListAssembler::default();
+        let mut assembled = None;
+        for page in pages.iter().rev() {
+            assert!(assembled.is_none());
+            let (generation, index, count, peers) = decode_page(page);
+            assembled = assembler.add(generation, index, count, peers);
+        }
+        let mut assembled = assembled.unwrap();
+        assembled.sort_by_key(|peer| peer.ip);
+        assert_eq!(assembled, all_peers);
+    }
+
+    #[test]
+    fn older_lists_are_ignored_and_newer_ones_replace_them() {
+        let mut assembler = PeerListAssembler::default();
+        let newer = peers(2);
+        assert_eq!(assembler.add(5, 0, 2, vec![newer[0]]), None);
+        assert_eq!(assembler.add(4, 1, 2, vec![newer[1]]), None);
+        assert_eq!(assembler.add(6, 0, 2, vec![newer[1]]), None);
+        assert_eq!(
+            assembler.add(6, 1, 2, vec![newer[0]]),
+            Some(vec![newer[1], newer[0]])
+        );
+        assert_eq!(assembler.add(5, 1, 2, vec![newer[1]]), None);
+    }
+
+    #[test]
+    fn rejects_bad_page_numbers() {
+        let mut assembler = PeerListAssembler::default();
+        assert_eq!(assembler.add(1, 0, 0, Vec::new()), None);
+        assert_eq!(assembler.add(1, 2, 2, Vec::new()), None);
+        assert_eq!(assembler.add(1, 0, MAX_PAGES + 1, Vec::new()), None);
+    }
+}
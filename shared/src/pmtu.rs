@@ -0,0 +1,41 @@
+use crate::{
+    crypto::TAG_LENGTH,
+    data::{SEQUENCE_LENGTH, TRANSPORT_HEADER_LENGTH},
+    Message, HEADER_LENGTH,
+};
+
+/// Everything in a datagram around the plaintext of a message
+const DATAGRAM_OVERHEAD: usize = HEADER_LENGTH + TRANSPORT_HEADER_LENGTH + TAG_LENGTH;
+/// Variant and padding length of an encoded `Message::MtuProbe`
+const PROBE_HEADER_LENGTH: usize = 4 + 8;
+/// Variant, sequence number and length of an encoded `Message::Data`
+const DATA_HEADER_LENGTH: usize = 4 + SEQUENCE_LENGTH + 8;
+
+/// Longest datagram worth probing for, what fits in a 1500 bytes Ethernet MTU
+/// after the IP and UDP headers
+pub fn max_probe_length(ipv6: bool) -> usize {
+    let ip_header_length = if ipv6 { 40 } else { 20 };
+    1500 - ip_header_length - 8
+}
+
+/// A [`Message::MtuProbe`] padded to be sent in a datagram of `datagram_length` bytes
+pub fn probe(datagram_length: usize) -> Message {
+    Message::MtuProbe {
+        padding: vec![0; datagram_length - DATAGRAM_OVERHEAD - PROBE_HEADER_LENGTH],
+    }
+}
+
+/// Length of the datagram a [`Message::MtuProbe`] with this much padding came in
+pub fn probe_datagram_length(padding_length: usize) -> usize {
+    DATAGRAM_OVERHEAD + PROBE_HEADER_LENGTH + padding_length
+}
+
+/// Longest plaintext that fits in a datagram of `datagram_length` bytes
+pub fn max_plaintext_length(datagram_length: usize) -> usize {
+    datagram_length - DATAGRAM_OVERHEAD
+}
+
+/// Longest ethernet frame a `Message::Data` in a datagram of `datagram_length` bytes carries
+pub fn max_frame_length(datagram_length: usize) -> usize {
+    max_plaintext_length(datagram_length) - DATA_HEADER_LENGTH
+}
@@ -0,0 +1,100 @@
+/// Sliding window of received counters, in blocks of 64 bits used as a ring buffer
+/// so moving the window forward doesn't need to shift the whole bitmap
+const WINDOW_BLOCKS: usize = 32;
+const BLOCK_BITS: u64 = u64::BITS as u64;
+/// One block is always being reused for the counters ahead of us
+pub const WINDOW_SIZE: u64 = (WINDOW_BLOCKS as u64 - 1) * BLOCK_BITS;
+
+#[derive(Default)]
+pub struct ReplayWindow {
+    highest: u64,
+    blocks: [u64; WINDOW_BLOCKS],
+}
+
+impl ReplayWindow {
+    /// Returns false if we have seen this counter or it's too old to tell,
+    /// only call this with counters from authenticated packets
+    pub fn check_and_update(&mut self, counter: u64) -> bool {
+        if self.highest.saturating_sub(counter) >= WINDOW_SIZE {
+            return false;
+        }
+        let block_index = counter / BLOCK_BITS;
+        if counter > self.highest {
+            let current_block_index = self.highest / BLOCK_BITS;
+            let blocks_to_clear = (block_index - current_block_index).min(WINDOW_BLOCKS as u64);
+            for i in 1..=blocks_to_clear {
+                self.blocks[((current_block_index + i) % WINDOW_BLOCKS as u64) as usize] = 0;
+            }
+            self.highest = counter;
+        }
+        let block = &mut self.blocks[(block_index % WINDOW_BLOCKS as u64) as usize];
+        let bit = 1 << (counter % BLOCK_BITS);
+        if *block & bit != 0 {
+            return false;
+        }
+        *block |= bit;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_in_order_counters() {
+        let mut window = ReplayWindow::default();
+        for counter in 0..1000 {
+            assert!(window.check_and_update(counter));
+        }
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        let mut window = ReplayWindow::default();
+        assert!(window.check_and_update(0));
+        assert!(!window.check_and_update(0));
+        assert!(window.check_and_update(5));
+        assert!(!window.check_and_update(5));
+        // Reordered within the window is fine, but only once
+        assert!(window.check_and_update(3));
+        assert!(!window.check_and_update(3));
+    }
+
+    #[test]
+    fn rejects_counters_too_old_to_tell() {
+        let mut window = ReplayWindow::default();
+        assert!(window.check_and_update(WINDOW_SIZE + 10));
+        assert!(!window.check_and_update(10));
+        assert!(window.check_and_update(11));
+        assert!(!window.check_and_update(0));
+    }
+
+    #[test]
+    fn jump_beyond_the_window_forgets_old_counters() {
+        let mut window = ReplayWindow::default();
+        for counter in 0..100 {
+            assert!(window.check_and_update(counter));
+        }
+        let far = 100 + 10 * WINDOW_SIZE;
+        assert!(window.check_and_update(far));
+        assert!(!window.check_and_update(far));
+        assert!(!window.check_and_update(99));
+        // Counters just behind the new highest were never seen, even though their blocks
+        // are reused from the old window
+        for counter in far - WINDOW_SIZE + 1..far {
+            assert!(window.check_and_update(counter));
+        }
+    }
+
+    #[test]
+    fn jump_by_less_than_the_window_keeps_recent_counters() {
+        let mut window = ReplayWindow::default();
+        for counter in 0..100 {
+            assert!(window.check_and_update(counter));
+        }
+        assert!(window.check_and_update(100 + WINDOW_SIZE / 2));
+        assert!(!window.check_and_update(99));
+        assert!(window.check_and_update(100));
+    }
+}
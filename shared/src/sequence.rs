@@ -0,0 +1,138 @@
+use std::fmt;
+
+/// Sequence numbers behind the highest one we remember seeing, older ones only count as reordered
+const WINDOW_SIZE: u64 = u64::BITS as u64;
+
+/// Data messages received from one peer since the stats were last taken
+#[derive(Default, Clone, Copy)]
+pub struct SequenceStats {
+    pub received: u64,
+    /// Skipped sequence numbers that haven't shown up (yet)
+    pub lost: u64,
+    pub duplicated: u64,
+    /// Arrived after a higher sequence number
+    pub reordered: u64,
+}
+
+impl fmt::Display for SequenceStats {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        let expected = self.received - self.duplicated + self.lost;
+        let loss = match expected {
+            0 => 0.0,
+            expected => self.lost as f64 / expected as f64 * 100.0,
+        };
+        write!(
+            formatter,
+            "received {}, lost {} ({loss:.1}%), duplicated {}, reordered {}",
+            self.received, self.lost, self.duplicated, self.reordered
+        )
+    }
+}
+
+/// Tracks the sequence numbers of data messages from one peer
+#[derive(Default)]
+pub struct SequenceTracker {
+    highest: Option<u64>,
+    /// Bit `n` is set if `highest - n` was received
+    window: u64,
+    stats: SequenceStats,
+}
+
+impl SequenceTracker {
+    pub fn receive(&mut self, sequence: u64) {
+        self.stats.received += 1;
+        let Some(highest) = self.highest else {
+            self.highest = Some(sequence);
+            self.window = 1;
+            return;
+        };
+        if sequence > highest {
+            let skipped = sequence - highest - 1;
+            self.stats.lost += skipped;
+            self.window = match sequence - highest {
+                distance if distance >= WINDOW_SIZE => 1,
+                distance => self.window << distance | 1,
+            };
+            self.highest = Some(sequence);
+            return;
+        }
+        let distance = highest - sequence;
+        if distance >= WINDOW_SIZE {
+            // Too old to tell if it's a duplicate, it was counted as lost when it got skipped
+            self.stats.reordered += 1;
+            self.stats.lost = self.stats.lost.saturating_sub(1);
+            return;
+        }
+        let bit = 1 << distance;
+        if self.window & bit != 0 {
+            self.stats.duplicated += 1;
+            return;
+        }
+        self.window |= bit;
+        self.stats.reordered += 1;
+        self.stats.lost = self.stats.lost.saturating_sub(1);
+    }
+
+    /// Stats since the last time they were taken
+    pub fn take_stats(&mut self) -> SequenceStats {
+        std::mem::take(&mut self.stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats_after(sequences: &[u64]) -> SequenceStats {
+        let mut tracker = SequenceTracker::default();
+        for &sequence in sequences {
+            tracker.receive(sequence);
+        }
+        tracker.take_stats()
+    }
+
+    #[test]
+    fn counts_in_order_messages() {
+        let stats = stats_after(&[0, 1, 2, 3]);
+        assert_eq!(
+            (
+                stats.received,
+                stats.lost,
+                stats.duplicated,
+                stats.reordered
+            ),
+            (4, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn counts_skipped_as_lost_until_they_arrive() {
+        let stats = stats_after(&[0, 3, 4]);
+        assert_eq!((stats.lost, stats.reordered), (2, 0));
+        let stats = stats_after(&[0, 3, 1, 2]);
+        assert_eq!((stats.lost, stats.reordered), (0, 2));
+    }
+
+    #[test]
+    fn counts_duplicates() {
+        let stats = stats_after(&[0, 1, 1, 0]);
+        assert_eq!((stats.received, stats.duplicated, stats.lost), (4, 2, 0));
+    }
+
+    #[test]
+    fn jump_beyond_the_window() {
+        let stats = stats_after(&[0, 1000, 1000, 500]);
+        assert_eq!((stats.lost, stats.duplicated, stats.reordered), (998, 1, 1));
+    }
+
+    #[test]
+    fn taking_stats_resets_them() {
+        let mut tracker = SequenceTracker::default();
+        tracker.receive(0);
+        tracker.receive(2);
+        assert_eq!(tracker.take_stats().lost, 1);
+        tracker.receive(3);
+        let stats = tracker.take_stats();
+        assert_eq!((stats.received, stats.lost), (1, 0));
+    }
+}